@@ -1,32 +1,182 @@
-use egui::{mutex::Mutex, Event, Id, InputState, RawInput, Rect, Sense, Ui, Vec2, Widget};
-use ewebsock::{WsEvent, WsMessage};
-use log::{info, trace};
+use egui::{
+    emath::TSTransform, mutex::Mutex, vec2, Align2, Color32, Event, EventFilter, FontId, Id,
+    InputState, Pos2, RawInput, Rect, Sense, Ui, UiBuilder, Vec2, ViewportId, Widget,
+};
+use log::{info, trace, warn};
 use meterm_common::{
-    delta_encoding::{self, Decoder},
-    deserialize,
+    codec::Codec,
+    compact::ShapeEncoding,
+    recording::Recorder,
+    transport::{LoopbackConnector, Transport},
     egui::{
-        self, epaint::{ClippedShape, TextShape}, Context, CursorIcon, FullOutput, Shape
+        self, epaint::{text::Fonts, ClippedShape, Galley, StrokeKind, TextShape}, Context, CursorIcon, FullOutput, PlatformOutput, Shape
     },
-    serialize, ClientToServer, ServerToClient,
+    hit_test::HitRegions,
+    stats::TrafficStats,
+    wire::WireFormat,
+    ClientHello, ClientToServer, ServerHello, SessionToken,
 };
-use std::sync::Arc;
+use std::{collections::HashMap, hash::Hash, path::PathBuf, sync::Arc, time::Duration};
+use link::LinkHandle;
+use websocket::WebSocket;
+
+#[cfg(not(target_arch = "wasm32"))]
+use meterm_common::transport::stream::StreamTransport;
+
+mod link;
+mod replay;
+mod websocket;
+pub use replay::ReplayWidget;
 
 #[derive(Clone, Debug)]
 pub struct ServerWidget {
     pub addr: String,
     pub desired_size: Vec2,
     pub debug_packets: bool,
+    pub shape_encoding: ShapeEncoding,
+    pub wire_format: WireFormat,
+    /// Where to record the session to, if anywhere
+    pub record_path: Option<PathBuf>,
+    pub record_input: bool,
+    /// Connect in-process instead of to `addr`
+    pub loopback: Option<LoopbackConnector>,
+    /// Reconnect on errors instead of showing them, if set
+    pub reconnect: Option<Backoff>,
+    /// Show hover highlights and cursors before the server gets to it
+    pub predict_hover: bool,
+    /// Draw [`ConnectionStats`] on top
+    pub show_stats: bool,
+    /// Take the size the server asks for, instead of `desired_size`
+    pub auto_size: bool,
+    /// How remote points map to ours
+    pub scale: ScaleMode,
+    /// Keep the keyboard on escape, see [`Self::lock_focus`]
+    pub lock_focus: bool,
+    /// Tells widgets apart, and with them their connections. The address if unset
+    pub id_source: Option<Id>,
+}
+
+/// Passes a widget may go without being shown before its connection is closed
+const FORGET_AFTER_PASSES: u64 = 10;
+
+/// How a [`ServerWidget`] scales the remote UI into its rect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleMode {
+    /// The remote UI lays out for this size, and is scaled to fit inside the
+    /// widget, keeping its aspect ratio. Bars are left empty
+    Fit(Vec2),
+    /// Like `Fit`, but scaled to cover all of the widget. What sticks out is cut off
+    Fill(Vec2),
+    /// One remote point is this many of ours. The remote UI lays out for
+    /// the widget's size, in its own points
+    Zoom(f32),
+}
+
+impl Default for ScaleMode {
+    fn default() -> Self {
+        Self::Zoom(1.0)
+    }
+}
+
+impl ScaleMode {
+    /// Size of the remote screen, when shown in a widget of `size`
+    pub fn screen_size(&self, size: Vec2) -> Vec2 {
+        match *self {
+            Self::Fit(screen_size) | Self::Fill(screen_size) => screen_size,
+            Self::Zoom(zoom) => size / zoom,
+        }
+    }
+
+    /// From remote points to ours, for a remote screen of `screen_size` shown in `rect`
+    pub fn transform(&self, rect: Rect, screen_size: Vec2) -> TSTransform {
+        let ratio = rect.size() / screen_size;
+        let scaling = match *self {
+            Self::Fit(_) => ratio.min_elem(),
+            Self::Fill(_) => ratio.max_elem(),
+            Self::Zoom(zoom) => return TSTransform::new(rect.min.to_vec2(), zoom),
+        };
+        // Centered
+        let translation = rect.center() - screen_size * scaling / 2.0;
+        TSTransform::new(translation.to_vec2(), scaling)
+    }
+}
+
+/// How long to wait between reconnect attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Before the first attempt
+    pub initial: Duration,
+    /// Each attempt waits this much longer than the previous one
+    pub factor: f32,
+    pub max: Duration,
+    /// Give up (and show the error) after this many failed attempts in a row
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            factor: 2.0,
+            max: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait before `attempt`, counting from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial.as_secs_f32() * self.factor.powi(exponent);
+        // With no initial wait, late attempts would be 0 times infinity
+        let secs = if secs.is_nan() { 0.0 } else { secs };
+        // A negative factor goes below zero, and a huge max lets infinity through
+        let secs = secs.clamp(0.0, self.max.as_secs_f32());
+        Duration::try_from_secs_f32(secs).unwrap_or(self.max)
+    }
 }
 
 impl ServerWidget {
+    /// `addr` is a `ws://` or `wss://` URL. Natively, `tcp://host:port`,
+    /// `unix:///path/to/socket` and `stdio:` (our own stdin/stdout) work too,
+    /// using length-prefixed frames instead of WebSockets
     pub fn new(addr: impl Into<String>) -> Self {
         Self {
             debug_packets: false,
             addr: addr.into(),
             desired_size: Vec2::new(200., 200.),
+            shape_encoding: ShapeEncoding::Standard,
+            wire_format: WireFormat::Bincode,
+            record_path: None,
+            record_input: false,
+            loopback: None,
+            reconnect: None,
+            predict_hover: false,
+            show_stats: false,
+            auto_size: false,
+            scale: ScaleMode::default(),
+            lock_focus: false,
+            id_source: None,
         }
     }
 
+    /// Show a server running in this same process (see `Server::loopback_connector`),
+    /// without any sockets. `name` takes the place of the address
+    pub fn loopback(name: impl Into<String>, connector: LoopbackConnector) -> Self {
+        Self {
+            loopback: Some(connector),
+            ..Self::new(name)
+        }
+    }
+
+    /// Needed to show the same address more than once, each with a connection
+    /// of its own. Otherwise, changing the address also changes the widget
+    pub fn id_source(mut self, id_source: impl Hash) -> Self {
+        self.id_source = Some(Id::new(id_source));
+        self
+    }
+
     pub fn with_desired_size(mut self, size: Vec2) -> Self {
         self.desired_size = size;
         self
@@ -36,27 +186,327 @@ impl ServerWidget {
         self.debug_packets = is_on;
         self
     }
+
+    /// Ask the server for quantized shapes; smaller, but positions are off by up to 1/16th of a point
+    pub fn compact_shapes(mut self, is_on: bool) -> Self {
+        self.shape_encoding = if is_on {
+            ShapeEncoding::Compact
+        } else {
+            ShapeEncoding::Standard
+        };
+        self
+    }
+
+    /// JSON is much larger, but readable in the browser's network inspector
+    pub fn wire_format(mut self, format: WireFormat) -> Self {
+        self.wire_format = format;
+        self
+    }
+
+    /// Record every frame received (and, optionally, our input) to a file, starting at connection.
+    /// An existing recording is appended to, as a new segment
+    pub fn record_to(mut self, path: impl Into<PathBuf>, record_input: bool) -> Self {
+        self.record_path = Some(path.into());
+        self.record_input = record_input;
+        self
+    }
+
+    /// Reconnect by itself when the connection drops, keeping the last frame
+    /// on screen meanwhile. The server's UI state survives, as long as it
+    /// still holds the session
+    pub fn auto_reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    /// Highlight widgets and change the cursor as soon as the pointer moves,
+    /// from where the server says its widgets are. Hides latency, at the cost
+    /// of some bandwidth whenever the layout changes
+    pub fn predict_hover(mut self, predict_hover: bool) -> Self {
+        self.predict_hover = predict_hover;
+        self
+    }
+
+    /// Be as large as the remote UI, like a regular widget. The server lays
+    /// out in all the room available, and then says how much of it it used
+    /// (or what it asked for with `meterm_server::utils::set_preferred_size`).
+    /// Panels use all the room they get, so the remote UI should lay out
+    /// without them. `desired_size` is used until the server answers.
+    /// Only for [`ScaleMode::Zoom`], since the others pick their own size
+    pub fn auto_size(mut self, auto_size: bool) -> Self {
+        self.auto_size = auto_size;
+        self
+    }
+
+    /// Shrink or grow the remote UI, see [`ScaleMode`]. Text is laid out
+    /// again at the new size, so it stays sharp
+    pub fn scale(mut self, scale: ScaleMode) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Overlay round trip time, frame rate and bandwidth in the top right corner
+    pub fn show_stats(mut self, show_stats: bool) -> Self {
+        self.show_stats = show_stats;
+        self
+    }
+
+    /// Pass escape on to the remote UI too, instead of giving the keyboard back
+    /// to the host. Clicking elsewhere still does
+    pub fn lock_focus(mut self, lock_focus: bool) -> Self {
+        self.lock_focus = lock_focus;
+        self
+    }
+
+    /// Give this widget the keyboard, as clicking it would. Escape, or
+    /// clicking elsewhere, takes it away again
+    pub fn request_focus(&self, ctx: &Context) {
+        ctx.memory_mut(|mem| mem.request_focus(self.id()));
+    }
+
+    /// Whether keys and text go to the remote UI
+    pub fn has_focus(&self, ctx: &Context) -> bool {
+        ctx.memory(|mem| mem.has_focus(self.id()))
+    }
+
+    /// Drop the connection and start over the next time this is shown, as the
+    /// "Try again" button does. For placeholders of your own
+    pub fn reconnect(&self, ctx: &Context) {
+        Connections::get(ctx).forget(self.id());
+    }
+
+    /// Stats of this widget's connection, if it has been shown and is connected
+    pub fn stats(&self, ctx: &Context) -> Option<ConnectionStats> {
+        let client = Connections::get(ctx).client(self.id())?;
+        let lck = client.lock();
+        match &*lck {
+            Client::Success(client) => Some(client.stats(ctx.input(|i| i.time))),
+            Client::Reconnecting(_) | Client::Failure { .. } => None,
+        }
+    }
 }
 
-impl Widget for ServerWidget {
-    fn ui(self, ui: &mut Ui) -> egui::Response {
-        let client = ui.ctx().memory_mut(|mem| {
-            mem.data
-                .get_temp_mut_or_insert_with(Id::new(&self.addr), || {
-                    Arc::new(Mutex::new(Client::connect(self.clone(), ui.ctx())))
-                })
-                .clone()
+/// What went over a [`ServerWidget`]'s connection so far
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub format: WireFormat,
+    /// Codec and shape encoding, once negotiated
+    pub hello: Option<ServerHello>,
+    /// Updates which made it to the screen
+    pub frames_decoded: u64,
+    pub traffic: TrafficStats,
+}
+
+/// Where a [`ServerWidget`]'s connection is at
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    /// Waiting for the server to answer
+    Connecting,
+    Connected {
+        /// Smoothed round trip time, once measured
+        rtt: Option<Duration>,
+    },
+    /// Lost, and trying again. Only with [`ServerWidget::auto_reconnect`]
+    Reconnecting {
+        error: String,
+        /// Counting from 1
+        attempt: u32,
+        retry_in: Duration,
+    },
+    /// Given up on
+    Failed { error: String },
+}
+
+/// Something that happened to a [`ServerWidget`]'s connection since it was last shown
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// The server answered our hello
+    Connected {
+        /// Whether the server's UI state carried over from the last connection
+        resumed: bool,
+    },
+    /// A connection which had been connected was lost
+    Disconnected { error: String },
+}
+
+/// What [`ServerWidget::show`] returns
+pub struct ServerWidgetOutput {
+    pub response: egui::Response,
+    pub status: ConnectionStatus,
+    pub events: Vec<ConnectionEvent>,
+}
+
+/// Shown in place of the remote UI while there's none
+type Placeholder<'a, 'f> = Option<&'a mut (dyn FnMut(&mut Ui, &ConnectionStatus) + 'f)>;
+
+/// Where the app's placeholder goes, if it has one. Found while the client is
+/// locked, shown once it isn't, since the placeholder may ask about the connection
+type PlaceholderAt<'a> = Option<&'a mut Option<(Rect, ConnectionStatus)>>;
+
+impl ServerWidget {
+    /// Like adding the widget, but with the connection's status
+    pub fn show(self, ui: &mut Ui) -> ServerWidgetOutput {
+        self.show_impl(ui, None)
+    }
+
+    /// Like [`Self::show`], but with `placeholder` instead of the built-in
+    /// messages while connecting, reconnecting or after failing. It gets a `Ui`
+    /// of the widget's size. See [`Self::reconnect`] for a "Try again" button
+    pub fn show_with_placeholder(
+        self,
+        ui: &mut Ui,
+        placeholder: impl FnOnce(&mut Ui, &ConnectionStatus),
+    ) -> ServerWidgetOutput {
+        let mut placeholder = Some(placeholder);
+        let mut placeholder = |ui: &mut Ui, status: &ConnectionStatus| {
+            if let Some(placeholder) = placeholder.take() {
+                placeholder(ui, status);
+            }
+        };
+        self.show_impl(ui, Some(&mut placeholder))
+    }
+
+    fn show_impl(self, ui: &mut Ui, placeholder: Placeholder) -> ServerWidgetOutput {
+        let id = self.id();
+        let connections = Connections::get(ui.ctx());
+        let client = connections.shown(ui.ctx(), id, &self.addr);
+        // Not while holding the connections, since connecting may use the context
+        let client = client.unwrap_or_else(|| {
+            let client = Arc::new(Mutex::new(Client::connect(self.clone(), ui.ctx())));
+            connections.insert(ui.ctx(), id, &self.addr, client.clone());
+            client
         });
 
         let mut lck = client.lock();
         lck.set_debug_delta_encoding_packets(self.debug_packets);
-        lck.show(ui, &self)
+        let mut events = vec![];
+        let mut placeholder_at = None;
+        let wants_placeholder = placeholder.is_some().then_some(&mut placeholder_at);
+        let response = lck.show(ui, &self, wants_placeholder, &mut events);
+        let status = lck.status(ui.input(|i| i.time));
+        drop(lck);
+
+        if let (Some(placeholder), Some((rect, status))) = (placeholder, placeholder_at) {
+            placeholder(&mut ui.new_child(UiBuilder::new().max_rect(rect)), &status);
+        }
+        ServerWidgetOutput {
+            response,
+            status,
+            events,
+        }
+    }
+
+    /// Of both the widget and its connection
+    fn id(&self) -> Id {
+        self.id_source.unwrap_or_else(|| Id::new(&self.addr))
+    }
+}
+
+impl Widget for ServerWidget {
+    fn ui(self, ui: &mut Ui) -> egui::Response {
+        self.show(ui).response
     }
 }
 
+/// Every [`ServerWidget`]'s connection, by widget id. Those whose widget is
+/// no longer shown are closed at the end of a pass
+#[derive(Clone, Default)]
+struct Connections(Arc<Mutex<HashMap<Id, Connection>>>);
+
+struct Connection {
+    addr: String,
+    client: Arc<Mutex<Client>>,
+    /// Of the root viewport, see [`Context::cumulative_pass_nr_for`]
+    last_shown: u64,
+}
+
+impl Connections {
+    /// Of `ctx`, set up on first use
+    fn get(ctx: &Context) -> Self {
+        let id = Id::new("meterm_client_connections");
+        if let Some(connections) = ctx.data(|data| data.get_temp::<Self>(id)) {
+            return connections;
+        }
+
+        let connections = Self::default();
+        ctx.data_mut(|data| data.insert_temp(id, connections.clone()));
+        let forget = connections.clone();
+        ctx.on_end_pass(
+            "meterm_client_connections",
+            Arc::new(move |ctx| forget.forget_unshown(ctx)),
+        );
+        connections
+    }
+
+    fn client(&self, id: Id) -> Option<Arc<Mutex<Client>>> {
+        self.0.lock().get(&id).map(|connection| connection.client.clone())
+    }
+
+    /// The connection of the widget `id`, if it's still to `addr`
+    fn shown(&self, ctx: &Context, id: Id, addr: &str) -> Option<Arc<Mutex<Client>>> {
+        let mut connections = self.0.lock();
+        let connection = connections.get_mut(&id)?;
+        if connection.addr != addr {
+            info!("Closing connection to {}, moving to {addr}", connection.addr);
+            connections.remove(&id);
+            return None;
+        }
+        connection.last_shown = pass_nr(ctx);
+        Some(connection.client.clone())
+    }
+
+    fn forget(&self, id: Id) {
+        self.0.lock().remove(&id);
+    }
+
+    fn insert(&self, ctx: &Context, id: Id, addr: &str, client: Arc<Mutex<Client>>) {
+        let connection = Connection {
+            addr: addr.into(),
+            client,
+            last_shown: pass_nr(ctx),
+        };
+        self.0.lock().insert(id, connection);
+    }
+
+    fn forget_unshown(&self, ctx: &Context) {
+        let pass_nr = pass_nr(ctx);
+        self.0.lock().retain(|_, connection| {
+            let shown = pass_nr.saturating_sub(connection.last_shown) <= FORGET_AFTER_PASSES;
+            if !shown {
+                info!("Closing connection to {}, its widget is gone", connection.addr);
+            }
+            shown
+        });
+    }
+}
+
+fn pass_nr(ctx: &Context) -> u64 {
+    ctx.cumulative_pass_nr_for(ViewportId::ROOT)
+}
+
 enum Client {
     Success(ClientImpl),
-    Failure { error: String },
+    Reconnecting(Reconnecting),
+    Failure {
+        error: String,
+        /// Kept for trying again
+        recorder: Option<Recorder>,
+    },
+}
+
+/// What's kept of a dropped connection until the next attempt
+struct Reconnecting {
+    error: String,
+    /// Counting from 1, reset by every connection which gets as far as the handshake
+    attempt: u32,
+    /// In `egui::InputState::time`
+    retry_at: f64,
+    last_frame: Option<FullOutput>,
+    /// Carries on in the same file
+    recorder: Option<Recorder>,
+    /// Asked for again, so the server UI keeps its state
+    session: Option<SessionToken>,
+    scaled_text: ScaledText,
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -67,39 +517,180 @@ unsafe impl Send for Client {}
 
 impl Client {
     fn connect(view: ServerWidget, ctx: &Context) -> Self {
-        let ctx = ctx.clone();
-        match ewebsock::connect_with_wakeup(&view.addr, Default::default(), move || {
-            ctx.request_repaint()
-        }) {
-            Ok((tx, rx)) => Self::Success(ClientImpl::new(tx, rx, view)),
-            Err(e) => Self::Failure {
-                error: format!("{:?}", e),
-            },
+        let recorder = open_recorder(&view);
+        Self::connect_attempt(view, ctx, 0, None, recorder, None)
+    }
+
+    fn connect_attempt(
+        view: ServerWidget,
+        ctx: &Context,
+        attempt: u32,
+        last_frame: Option<FullOutput>,
+        recorder: Option<Recorder>,
+        session: Option<SessionToken>,
+    ) -> Self {
+        let hello = ClientHello {
+            codecs: Codec::supported(),
+            shape_encoding: view.shape_encoding,
+            resume: session,
+            hit_regions: view.predict_hover,
+        };
+        let mut recorder = recorder;
+        match LinkHandle::open(&view, ctx, hello, &mut recorder) {
+            Ok(link) => {
+                let mut client = ClientImpl::new(link, view);
+                client.latest_frame = last_frame;
+                client.attempt = attempt;
+                client.resume = session;
+                Self::Success(client)
+            }
+            Err(error) => Self::after_error(
+                &view,
+                ctx,
+                error,
+                attempt + 1,
+                last_frame,
+                recorder,
+                session,
+            ),
         }
     }
 
-    fn show(&mut self, ui: &mut Ui, view: &ServerWidget) -> egui::Response {
+    /// Wait for the next attempt, or give up if we aren't supposed to reconnect
+    fn after_error(
+        view: &ServerWidget,
+        ctx: &Context,
+        error: String,
+        attempt: u32,
+        last_frame: Option<FullOutput>,
+        recorder: Option<Recorder>,
+        session: Option<SessionToken>,
+    ) -> Self {
+        let Some(backoff) = &view.reconnect else {
+            return Self::Failure { error, recorder };
+        };
+        if backoff.max_attempts.is_some_and(|max| attempt > max) {
+            return Self::Failure { error, recorder };
+        }
+
+        let delay = backoff.delay(attempt);
+        warn!("{error}; reconnecting in {:.1}s", delay.as_secs_f32());
+        ctx.request_repaint_after(delay);
+
+        Self::Reconnecting(Reconnecting {
+            error,
+            attempt,
+            retry_at: ctx.input(|i| i.time) + delay.as_secs_f64(),
+            last_frame,
+            recorder,
+            session,
+            scaled_text: ScaledText::default(),
+        })
+    }
+
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        view: &ServerWidget,
+        mut placeholder: PlaceholderAt,
+        events: &mut Vec<ConnectionEvent>,
+    ) -> egui::Response {
         match self {
-            Self::Failure { error } => {
+            // Strictly after, so a connection failing right away waits at least a frame
+            Self::Reconnecting(state) if ui.input(|i| i.time) > state.retry_at => {
+                let Self::Reconnecting(state) = self.take() else {
+                    unreachable!()
+                };
+                info!("Reconnecting, attempt {}", state.attempt);
+                *self = Self::connect_attempt(
+                    view.clone(),
+                    ui.ctx(),
+                    state.attempt,
+                    state.last_frame,
+                    state.recorder,
+                    state.session,
+                );
+                self.show(ui, view, placeholder, events)
+            }
+            Self::Failure { .. } | Self::Reconnecting(_) if placeholder.is_some() => {
+                let status = self.status(ui.input(|i| i.time));
+                let resp = ui.allocate_response(view.desired_size, Sense::hover());
+                if let Some(placeholder) = placeholder {
+                    *placeholder = Some((resp.rect, status));
+                }
+                if let Self::Reconnecting(state) = self {
+                    state.keep_counting(ui);
+                }
+                resp
+            }
+            Self::Failure { error, recorder } => {
                 ui.label(format!("Error; {error}"));
                 let resp = ui.button("Try again");
                 if resp.clicked() {
-                    *self = Self::connect(view.clone(), ui.ctx());
+                    let recorder = recorder.take().or_else(|| open_recorder(view));
+                    *self = Self::connect_attempt(view.clone(), ui.ctx(), 0, None, recorder, None);
                 }
                 resp
             },
-            Self::Success(client) => match client.show(ui) {
+            Self::Reconnecting(state) => state.show(ui, view),
+            Self::Success(client) => match client.show(ui, view, placeholder.as_deref_mut()) {
                 Err(error) => {
-                    let mut fail = Self::Failure { error };
-                    let resp = fail.show(ui, view);
-                    *self = fail;
+                    events.append(&mut client.events);
+                    if client.hello.is_some() {
+                        events.push(ConnectionEvent::Disconnected {
+                            error: error.clone(),
+                        });
+                    }
+                    // A connection which got through the handshake was a success, so start over
+                    let attempt = if client.hello.is_some() { 1 } else { client.attempt + 1 };
+                    let Self::Success(client) = self.take() else {
+                        unreachable!()
+                    };
+                    let session = client.hello.map(|hello| hello.session).or(client.resume);
+                    *self = Self::after_error(
+                        view,
+                        ui.ctx(),
+                        error,
+                        attempt,
+                        client.latest_frame,
+                        client.recorder,
+                        session,
+                    );
+                    self.show(ui, view, placeholder, events)
+                }
+                Ok(resp) => {
+                    events.append(&mut client.events);
                     resp
                 }
-                Ok(resp) => resp,
             },
         }
     }
 
+    fn status(&self, now: f64) -> ConnectionStatus {
+        match self {
+            Self::Success(client) => client.status(now),
+            Self::Reconnecting(state) => ConnectionStatus::Reconnecting {
+                error: state.error.clone(),
+                attempt: state.attempt,
+                retry_in: Duration::from_secs_f64((state.retry_at - now).max(0.0)),
+            },
+            Self::Failure { error, .. } => ConnectionStatus::Failed {
+                error: error.clone(),
+            },
+        }
+    }
+
+    /// Move out, leaving a placeholder to be overwritten
+    fn take(&mut self) -> Self {
+        std::mem::replace(
+            self,
+            Self::Failure {
+                error: String::new(),
+                recorder: None,
+            },
+        )
+    }
+
     fn set_debug_delta_encoding_packets(&mut self, is_on: bool) {
         if let Self::Success(client) = self {
             client.set_debug_delta_encoding_packets(is_on);
@@ -107,137 +698,619 @@ impl Client {
     }
 }
 
+impl Reconnecting {
+    /// The last frame, greyed out, with a note on top
+    fn show(&mut self, ui: &mut Ui, view: &ServerWidget) -> egui::Response {
+        let resp = ui.allocate_response(view.desired_size, Sense::hover());
+
+        if let Some(full_output) = &self.last_frame {
+            let screen_size = view.scale.screen_size(resp.rect.size());
+            let transform = view.scale.transform(resp.rect, screen_size);
+            paint_shapes(ui, resp.rect, transform, &full_output.shapes, &mut self.scaled_text);
+        }
+
+        let remaining = (self.retry_at - ui.input(|i| i.time)).max(0.0);
+        let countdown = remaining.ceil();
+        let painter = ui.painter_at(resp.rect);
+        painter.rect_filled(resp.rect, 0.0, Color32::from_black_alpha(160));
+        painter.text(
+            resp.rect.center(),
+            Align2::CENTER_CENTER,
+            format!(
+                "Reconnecting in {countdown}s (attempt {})…\n{}",
+                self.attempt, self.error
+            ),
+            FontId::proportional(14.0),
+            Color32::WHITE,
+        );
+
+        self.keep_counting(ui);
+
+        resp
+    }
+
+    /// Repaint in time for the countdown, and the next attempt
+    fn keep_counting(&self, ui: &Ui) {
+        let remaining = (self.retry_at - ui.input(|i| i.time)).max(0.0);
+        ui.ctx()
+            .request_repaint_after(Duration::from_secs_f64(remaining.min(1.0)));
+    }
+}
+
+/// `wakeup` is called whenever there's something to receive
+fn open_transport(
+    view: &ServerWidget,
+    wakeup: impl Fn() + Send + Sync + 'static,
+) -> Result<Box<dyn Transport>, String> {
+    match &view.loopback {
+        Some(connector) => {
+            let loopback = connector.connect();
+            loopback.set_wakeup(wakeup);
+            Ok(Box::new(loopback))
+        }
+        None => connect_transport(&view.addr, wakeup),
+    }
+}
+
+fn open_recorder(view: &ServerWidget) -> Option<Recorder> {
+    let path = view.record_path.as_ref()?;
+    Recorder::create(path)
+        .map(|recorder| recorder.with_input(view.record_input))
+        .map_err(|e| warn!("Failed to start recording to {}; {e}", path.display()))
+        .ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_transport(
+    addr: &str,
+    wakeup: impl Fn() + Send + Sync + 'static,
+) -> Result<Box<dyn Transport>, String> {
+    let stream = if let Some(addr) = addr.strip_prefix("tcp://") {
+        std::net::TcpStream::connect(addr).and_then(StreamTransport::tcp)
+    } else if let Some(path) = addr.strip_prefix("unix://") {
+        #[cfg(unix)]
+        let stream = std::os::unix::net::UnixStream::connect(path).and_then(StreamTransport::unix);
+        #[cfg(not(unix))]
+        let stream = Err(std::io::Error::other(format!("No unix sockets here; {path}")));
+        stream
+    } else if addr == "stdio:" {
+        Ok(StreamTransport::stdio())
+    } else {
+        return Ok(Box::new(WebSocket::connect(addr, wakeup)?));
+    };
+
+    let stream = stream.map_err(|e| format!("{addr}; {e}"))?;
+    stream.set_wakeup(wakeup);
+    Ok(Box::new(stream))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn connect_transport(
+    addr: &str,
+    wakeup: impl Fn() + Send + Sync + 'static,
+) -> Result<Box<dyn Transport>, String> {
+    Ok(Box::new(WebSocket::connect(addr, wakeup)?))
+}
+
 struct ClientImpl {
-    tx: ewebsock::WsSender,
-    rx: ewebsock::WsReceiver,
+    /// Does the receiving and decoding, see [`link`]
+    link: LinkHandle,
     view: ServerWidget,
     latest_frame: Option<FullOutput>,
-    open: bool,
-    decoder: delta_encoding::Decoder,
+    /// Set once the server answered our hello
+    hello: Option<ServerHello>,
+    /// Handed back by the link once the connection is over
+    recorder: Option<Recorder>,
+    /// Which reconnect attempt this is, 0 for the first connection
+    attempt: u32,
+    /// Session of the last connection, see [`ClientHello::resume`]
+    resume: Option<SessionToken>,
+    /// Latest from the server, if asked for
+    hit_regions: Option<HitRegions>,
+    /// What the server would like, see [`ServerWidget::auto_size`]
+    remote_size: Option<Vec2>,
+    /// Where the pointer was last frame, so the remote UI hears once that it's gone
+    pointer: PointerRoute,
+    /// Not yet returned from [`ServerWidget::show`]
+    events: Vec<ConnectionEvent>,
+    scaled_text: ScaledText,
 }
 
 impl ClientImpl {
-    fn new(tx: ewebsock::WsSender, rx: ewebsock::WsReceiver, view: ServerWidget) -> Self {
+    fn new(link: LinkHandle, view: ServerWidget) -> Self {
         Self {
-            tx,
-            rx,
+            link,
             view,
             latest_frame: None,
-            open: false,
-            decoder: Decoder::new(),
-        }
-    }
-
-    fn show(&mut self, ui: &mut Ui) -> Result<egui::Response, String> {
-        // Receive messages from server
-        loop {
-            match self.rx.try_recv() {
-                Some(WsEvent::Opened) => dbg!(self.open = true),
-                Some(WsEvent::Message(WsMessage::Binary(msg))) => {
-                    //trace!("Length {}", msg.len());
-                    let packet: ServerToClient = deserialize(&msg).expect("Deserialize");
-                    if let Some(full_output) = self.decoder.decode(packet.update.clone()) {
-                        let full_output = doctor_frame(full_output, ui.ctx());
-                        self.latest_frame = Some(full_output);
-                    }
-                }
-                Some(WsEvent::Error(e)) => return Err(format!("{e:#?}")),
-                _ => break,
-            }
+            hello: None,
+            recorder: None,
+            attempt: 0,
+            resume: None,
+            hit_regions: None,
+            remote_size: None,
+            pointer: PointerRoute::Outside,
+            events: vec![],
+            scaled_text: ScaledText::default(),
         }
+    }
 
-        // Allocate some space
-        let resp = ui.allocate_response(self.view.desired_size, Sense::click_and_drag());
-
-        // Draw the server contents
-        if let Some(full_output) = &mut self.latest_frame {
-            let offset = resp.rect.left_top().to_vec2();
-
-            // Draw offset shapes
-            for ClippedShape { clip_rect, shape } in &full_output.shapes {
-                let mut shape = shape.clone();
-                shape.translate(offset);
-                ui.set_clip_rect(clip_rect.translate(offset));
-                ui.painter().add(shape.clone());
-            }
-
-            // TODO: Sync textures!
-
-            // Handle platform output
-            let pt = &mut full_output.platform_output;
-            ui.ctx().output_mut(|client| {
-                if client.open_url.is_none() {
-                    client.open_url = pt.open_url.take();
-                }
-
-                if pt.cursor_icon != CursorIcon::default() {
-                    client.cursor_icon = pt.cursor_icon;
-                }
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        view: &ServerWidget,
+        placeholder: PlaceholderAt,
+    ) -> Result<egui::Response, String> {
+        // Size and such may change from frame to frame
+        self.view = view.clone();
 
-                if !pt.copied_text.is_empty() && client.copied_text.is_empty() {
-                    client.copied_text = pt.copied_text.clone();
-                }
-
-                client.mutable_text_under_cursor |= pt.mutable_text_under_cursor;
+        // Pick up what the link decoded since, only the latest frame matters
+        self.link.poll();
+        let mut inbox = self.link.inbox();
+        let frame = inbox.frame.take();
+        if self.hello.is_none() {
+            self.hello = inbox.hello.clone();
+        }
+        if inbox.hit_regions.is_some() {
+            self.hit_regions = inbox.hit_regions.take();
+        }
+        if inbox.size.is_some() {
+            self.remote_size = inbox.size.take();
+        }
+        self.events.append(&mut inbox.events);
+        if let Some((error, recorder)) = inbox.closed.take() {
+            self.recorder = recorder;
+            return Err(error);
+        }
+        drop(inbox);
 
-                if client.accesskit_update.is_none() {
-                    client.accesskit_update = pt.accesskit_update.take();
-                }
+        // Text is laid out here, with the fonts as they are this pass
+        if let Some(full_output) = frame {
+            self.latest_frame = Some(doctor_frame(full_output, ui.ctx()));
+        }
 
-                if client.ime.is_none() {
-                    let mut ime = pt.ime.take();
-                    if let Some(ime) = &mut ime {
-                        ime.rect = ime.rect.translate(offset);
-                        ime.cursor_rect = ime.cursor_rect.translate(offset);
-                    }
-                    client.ime = ime;
-                }
+        // Allocate some space
+        let (size, screen_size) = match (self.view.auto_size, self.view.scale) {
+            (true, ScaleMode::Zoom(zoom)) => {
+                // Offer all the room there is, where that's a finite amount
+                let available = ui.available_size();
+                let available = vec2(
+                    finite_or(available.x, self.view.desired_size.x),
+                    finite_or(available.y, self.view.desired_size.y),
+                );
+                let size = self
+                    .remote_size
+                    .map_or(self.view.desired_size, |size| size * zoom);
+                (size.min(available), Some(available / zoom))
+            }
+            _ => (self.view.desired_size, None),
+        };
+        let (_, rect) = ui.allocate_space(size);
+        let resp = ui.interact(rect, self.view.id(), Sense::click_and_drag());
+        // Pressing elsewhere takes the focus away again
+        if resp.is_pointer_button_down_on() {
+            resp.request_focus();
+        }
+        let has_focus = resp.has_focus();
+        if has_focus {
+            // Tab and arrows move around the remote UI. Escape leaves it
+            let filter = EventFilter {
+                tab: true,
+                horizontal_arrows: true,
+                vertical_arrows: true,
+                escape: self.view.lock_focus,
+            };
+            ui.memory_mut(|mem| mem.set_focus_lock_filter(resp.id, filter));
+        }
+        let pointer = if resp.contains_pointer() || resp.is_pointer_button_down_on() {
+            PointerRoute::Inside
+        } else if resp.dragged() || resp.drag_stopped() {
+            PointerRoute::Dragging
+        } else {
+            PointerRoute::Outside
+        };
+        let pointer_left = pointer == PointerRoute::Outside && self.pointer != PointerRoute::Outside;
+        self.pointer = pointer;
+        let screen_size =
+            screen_size.unwrap_or_else(|| self.view.scale.screen_size(resp.rect.size()));
+        let transform = self.view.scale.transform(resp.rect, screen_size);
 
-                client.events.extend(pt.events.drain(..));
-            });
+        // Draw the server contents, or the app's placeholder until there are any
+        let status = self.status(ui.input(|i| i.time));
+        match (&mut self.latest_frame, placeholder) {
+            (Some(full_output), _) => {
+                paint_shapes(ui, resp.rect, transform, &full_output.shapes, &mut self.scaled_text);
+                // TODO: Sync textures!
+                apply_platform_output(ui, transform, &mut full_output.platform_output);
+            }
+            (None, Some(placeholder)) => *placeholder = Some((resp.rect, status)),
+            (None, None) => (),
+        }
+        if let Some(regions) = &self.hit_regions {
+            predict_hover(ui, resp.rect, transform, regions);
+        }
+        if self.view.show_stats {
+            stats_overlay(ui, resp.rect, &self.stats(ui.input(|i| i.time)));
         }
 
         // Capture input
         let raw_input = ui
             .ctx()
-            .input(|input_state| convert_subwindow_input(input_state, resp.rect));
+            .input(|input_state| {
+                let mut raw = convert_subwindow_input(input_state, transform, screen_size);
+                filter_input(&mut raw, has_focus, pointer, pointer_left);
+                raw
+            });
+        #[cfg(not(target_arch = "wasm32"))]
+        let raw_input = load_dropped_files(raw_input);
 
         // Send response
-        if self.open {
-            self.tx.send(WsMessage::Binary(
-                serialize(&ClientToServer { raw_input }).unwrap(),
-            ))
+        let now = ui.input(|i| i.time);
+        let mut inbox = self.link.inbox();
+        if inbox.open {
+            let packet = ClientToServer {
+                raw_input,
+                // Changes with the browser's zoom, too
+                pixels_per_point: ui.ctx().pixels_per_point() * transform.scaling,
+                ping: inbox.meter.ping(now),
+                pong: inbox.meter.pong(),
+            };
+            drop(inbox);
+            self.link.send(packet);
         }
 
         Ok(resp)
     }
 
+    fn status(&self, now: f64) -> ConnectionStatus {
+        match self.hello {
+            Some(_) => ConnectionStatus::Connected {
+                rtt: self.link.inbox().meter.stats(now, None).rtt,
+            },
+            None => ConnectionStatus::Connecting,
+        }
+    }
+
+    fn stats(&self, now: f64) -> ConnectionStats {
+        let inbox = self.link.inbox();
+        let mut traffic = inbox.meter.stats(now, None);
+        traffic.raw_bytes_sent = inbox.raw_bytes_sent;
+        traffic.raw_bytes_received = inbox.raw_bytes_received;
+        ConnectionStats {
+            format: self.view.wire_format,
+            hello: self.hello.clone(),
+            frames_decoded: inbox.frames_decoded,
+            traffic,
+        }
+    }
+
     fn set_debug_delta_encoding_packets(&mut self, is_on: bool) {
-        self.decoder.debug_mode = is_on;
+        self.link.inbox().debug_packets = is_on;
+    }
+}
+
+fn finite_or(value: f32, fallback: f32) -> f32 {
+    if value.is_finite() {
+        value
+    } else {
+        fallback
     }
 }
 
-fn convert_subwindow_input(input_state: &InputState, rect: Rect) -> RawInput {
+/// Draw a frame's shapes into `rect`, `transform`ed from remote points.
+/// Nothing is drawn outside of `rect` or the `ui`'s clip rect
+fn paint_shapes(
+    ui: &Ui,
+    rect: Rect,
+    transform: TSTransform,
+    shapes: &[ClippedShape],
+    scaled_text: &mut ScaledText,
+) {
+    scaled_text.next_pass(ui.ctx());
+    let clip = ui.clip_rect().intersect(rect);
+    let mut painter = ui.painter().clone();
+    for ClippedShape { clip_rect, shape } in shapes {
+        let mut shape = shape.clone();
+        match &mut shape {
+            // Stretching the glyphs would blur them
+            Shape::Text(text) if transform.scaling != 1.0 => {
+                scale_text(text, transform, scaled_text, ui.ctx())
+            }
+            shape => shape.transform(transform),
+        }
+        painter.set_clip_rect((transform * *clip_rect).intersect(clip));
+        painter.add(shape);
+    }
+}
+
+/// Galleys laid out again by [`scale_text`], so that's only done once per
+/// frame and scale. Whatever wasn't painted the pass before is dropped
+#[derive(Default)]
+struct ScaledText {
+    /// By the address of the unscaled galley, which is kept so it isn't reused,
+    /// and the scale's bits
+    current: HashMap<(usize, u32), (Arc<Galley>, Arc<Galley>)>,
+    previous: HashMap<(usize, u32), (Arc<Galley>, Arc<Galley>)>,
+    /// The fonts change with it
+    pixels_per_point: f32,
+}
+
+impl ScaledText {
+    fn next_pass(&mut self, ctx: &Context) {
+        self.previous = std::mem::take(&mut self.current);
+        if self.pixels_per_point != ctx.pixels_per_point() {
+            self.pixels_per_point = ctx.pixels_per_point();
+            self.previous.clear();
+        }
+    }
+
+    fn get(
+        &mut self,
+        galley: &Arc<Galley>,
+        scale: f32,
+        layout: impl FnOnce() -> Arc<Galley>,
+    ) -> Arc<Galley> {
+        let key = (Arc::as_ptr(galley) as usize, scale.to_bits());
+        let previous = &mut self.previous;
+        let (_, scaled) = self.current.entry(key).or_insert_with(|| {
+            previous
+                .remove(&key)
+                .unwrap_or_else(|| (galley.clone(), layout()))
+        });
+        scaled.clone()
+    }
+}
+
+/// Lay out `text` again with its sizes scaled, and move it into place
+fn scale_text(
+    text: &mut TextShape,
+    transform: TSTransform,
+    scaled_text: &mut ScaledText,
+    ctx: &Context,
+) {
+    let scale = transform.scaling;
+    text.galley = scaled_text.get(&text.galley, scale, || {
+        let mut job = Arc::unwrap_or_clone(text.galley.job.clone());
+        for section in &mut job.sections {
+            section.leading_space *= scale;
+            let format = &mut section.format;
+            format.font_id.size *= scale;
+            format.extra_letter_spacing *= scale;
+            format.line_height = format.line_height.map(|height| height * scale);
+            format.underline.width *= scale;
+            format.strikethrough.width *= scale;
+        }
+        job.wrap.max_width *= scale;
+        job.first_row_min_height *= scale;
+        ctx.fonts(|fonts| fonts.layout_job(job))
+    });
+    text.pos = transform * text.pos;
+    text.underline.width *= scale;
+}
+
+fn stats_overlay(ui: &Ui, rect: Rect, stats: &ConnectionStats) {
+    let traffic = &stats.traffic;
+    let rtt = match traffic.rtt {
+        Some(rtt) => format!("{:.0} ms", rtt.as_secs_f64() * 1e3),
+        None => "?".into(),
+    };
+    let text = format!(
+        "RTT {rtt}, {:.0} fps\n\
+         ↓ {} ↑ {}, compression {:.1}x\n\
+         {} full, {} partial, {:.0}% reused",
+        traffic.fps,
+        format_bytes(traffic.bytes_received),
+        format_bytes(traffic.bytes_sent),
+        traffic.compression_ratio(),
+        traffic.full_updates,
+        traffic.partial_updates,
+        traffic.reference_hit_rate() * 100.0,
+    );
+
+    let painter = ui.painter().with_clip_rect(rect);
+    let galley = painter.layout_no_wrap(text, FontId::monospace(11.0), Color32::WHITE);
+    let margin = Vec2::splat(4.0);
+    let pos = rect.right_top() + vec2(-galley.size().x - margin.x * 3.0, margin.y);
+    let background = Rect::from_min_size(pos, galley.size() + margin * 2.0);
+    painter.rect_filled(background, 3.0, Color32::from_black_alpha(180));
+    painter.galley(pos + margin, galley, Color32::WHITE);
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1_000 => format!("{bytes} B"),
+        1_000..1_000_000 => format!("{:.1} kB", bytes as f64 / 1e3),
+        _ => format!("{:.1} MB", bytes as f64 / 1e6),
+    }
+}
+
+/// Show the region under the pointer as hovered, until a frame from the server
+/// has it hovered too. Nothing is hovered while a button is held, as in egui
+fn predict_hover(ui: &Ui, rect: Rect, transform: TSTransform, regions: &HitRegions) {
+    let Some(pointer) = ui.input(|i| i.pointer.hover_pos()) else {
+        return;
+    };
+    if !rect.contains(pointer) || ui.input(|i| i.pointer.any_down()) {
+        return;
+    }
+
+    let hit = regions.at(transform.inverse() * pointer);
+    if hit == regions.hovered() {
+        return;
+    }
+    let hit = hit.map(|idx| &regions.regions[idx]);
+
+    let cursor = match hit {
+        Some(region) => region.cursor,
+        None => Some(CursorIcon::Default),
+    };
+    if let Some(cursor) = cursor {
+        ui.ctx().set_cursor_icon(cursor);
+    }
+
+    if let Some(region) = hit.filter(|region| region.highlight) {
+        let visuals = &regions.visuals;
+        let scale = transform.scaling;
+        let mut stroke = visuals.stroke;
+        stroke.width *= scale;
+        ui.painter().with_clip_rect(rect).rect_stroke(
+            (transform * region.rect).expand(visuals.expansion * scale),
+            visuals.corner_radius * scale,
+            stroke,
+            StrokeKind::Inside,
+        );
+    }
+}
+
+fn apply_platform_output(ui: &Ui, transform: TSTransform, pt: &mut PlatformOutput) {
+    ui.ctx().output_mut(|client| {
+        if client.open_url.is_none() {
+            client.open_url = pt.open_url.take();
+        }
+
+        if pt.cursor_icon != CursorIcon::default() {
+            client.cursor_icon = pt.cursor_icon;
+        }
+
+        if !pt.copied_text.is_empty() && client.copied_text.is_empty() {
+            client.copied_text = pt.copied_text.clone();
+        }
+
+        client.mutable_text_under_cursor |= pt.mutable_text_under_cursor;
+
+        if client.accesskit_update.is_none() {
+            client.accesskit_update = pt.accesskit_update.take();
+        }
+
+        if client.ime.is_none() {
+            let mut ime = pt.ime.take();
+            if let Some(ime) = &mut ime {
+                ime.rect = transform * ime.rect;
+                ime.cursor_rect = transform * ime.cursor_rect;
+            }
+            client.ime = ime;
+        }
+
+        client.events.extend(pt.events.drain(..));
+    });
+}
+
+/// Input for a remote UI of `screen_size`, shown `transform`ed from remote points.
+/// Everything with a position or size in it ends up in remote points
+fn convert_subwindow_input(
+    input_state: &InputState,
+    transform: TSTransform,
+    screen_size: Vec2,
+) -> RawInput {
     let mut raw = input_state.raw.clone();
+    // The server lays out for the widget, not our whole window
+    raw.screen_rect = Some(Rect::from_min_size(Pos2::ZERO, screen_size));
+    let inverse = transform.inverse();
     for ev in &mut raw.events {
         match ev {
-            Event::PointerMoved(new_pos) => {
-                *new_pos -= rect.left_top().to_vec2();
+            Event::PointerMoved(pos)
+            | Event::PointerButton { pos, .. }
+            | Event::Touch { pos, .. } => {
+                *pos = inverse * *pos;
             }
-            Event::PointerButton { pos, .. } => {
-                *pos -= rect.left_top().to_vec2();
+            // Scroll by as much as the pointer would have to move
+            Event::MouseWheel {
+                unit: egui::MouseWheelUnit::Point,
+                delta,
+                ..
+            } => {
+                *delta *= inverse.scaling;
             }
+            // Zooming is around the pointer, which moved above
             _ => (),
         }
     }
 
+    // To the remote UI, the widget is a window of its own
+    let mut info = raw.viewports.remove(&raw.viewport_id).unwrap_or_default();
+    let window = info.inner_rect.map_or(Vec2::ZERO, |rect| rect.min.to_vec2());
+    let screen = Rect::from_min_size(
+        ((window + transform.translation) * inverse.scaling).to_pos2(),
+        screen_size,
+    );
+    info.parent = None;
+    info.events.clear();
+    info.inner_rect = Some(screen);
+    info.outer_rect = Some(screen);
+    info.monitor_size = info.monitor_size.map(|size| size * inverse.scaling);
+    raw.viewport_id = ViewportId::ROOT;
+    raw.viewports = std::iter::once((ViewportId::ROOT, info)).collect();
+
+    raw
+}
+
+/// Which of the pointer's events the remote UI gets, see [`filter_input`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PointerRoute {
+    /// Over the widget, or pressed on it
+    Inside,
+    /// Dragged off the widget, up to and including the release
+    Dragging,
+    Outside,
+}
+
+/// Keep keys and text only if the widget `has_focus`, and the pointer only if it's ours.
+/// A drag which started here keeps moving and gets its release, so nothing stays grabbed.
+/// Otherwise, as far as the remote UI knows, the pointer is gone once it `pointer_left`
+fn filter_input(raw: &mut RawInput, has_focus: bool, pointer: PointerRoute, pointer_left: bool) {
+    let has_pointer = pointer == PointerRoute::Inside;
+    let follows_pointer = pointer != PointerRoute::Outside;
+    // The remote text cursor stops blinking
+    raw.focused &= has_focus;
+    for info in raw.viewports.values_mut() {
+        info.focused = info.focused.map(|focused| focused && has_focus);
+    }
+    // Dropped where the pointer is
+    if !has_pointer {
+        raw.hovered_files.clear();
+        raw.dropped_files.clear();
+    }
+    raw.events.retain(|ev| match ev {
+        Event::Key { .. }
+        | Event::Text(_)
+        | Event::Copy
+        | Event::Cut
+        | Event::Paste(_)
+        | Event::Ime(_) => has_focus,
+        Event::PointerMoved(_)
+        | Event::MouseMoved(_)
+        | Event::PointerButton { pressed: false, .. } => follows_pointer,
+        Event::PointerButton { .. }
+        | Event::MouseWheel { .. }
+        | Event::Zoom(_)
+        | Event::Touch { .. } => has_pointer,
+        _ => true,
+    });
+    if pointer_left {
+        raw.events.push(Event::PointerGone);
+    }
+}
+
+/// The server can't read our files, so send their contents along
+#[cfg(not(target_arch = "wasm32"))]
+fn load_dropped_files(mut raw: RawInput) -> RawInput {
+    for file in &mut raw.dropped_files {
+        let Some(path) = file.path.as_ref().filter(|_| file.bytes.is_none()) else {
+            continue;
+        };
+        match std::fs::read(path) {
+            Ok(bytes) => file.bytes = Some(bytes.into()),
+            Err(e) => warn!("Failed to read dropped file {}; {e}", path.display()),
+        }
+    }
     raw
 }
 
 fn doctor_frame(mut full: FullOutput, ctx: &Context) -> FullOutput {
+    // Laid out with a handle of our own, since `Context::fonts` locks the whole context
+    let fonts = ctx.fonts(|fonts| fonts.clone());
     for shape in &mut full.shapes {
         match &mut shape.shape {
-            Shape::Text(text) => doctor_text(text, ctx),
+            Shape::Text(text) => doctor_text(text, &fonts),
             _ => (),
         }
     }
@@ -245,6 +1318,118 @@ fn doctor_frame(mut full: FullOutput, ctx: &Context) -> FullOutput {
     full
 }
 
-fn doctor_text(text: &mut TextShape, ctx: &Context) {
-    text.galley = ctx.fonts(|r| r.layout_job(Arc::unwrap_or_clone(text.galley.job.clone())));
+fn doctor_text(text: &mut TextShape, fonts: &Fonts) {
+    text.galley = fonts.layout_job(Arc::unwrap_or_clone(text.galley.job.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            factor: 3.0,
+            max: Duration::from_secs(2),
+            max_attempts: None,
+        };
+        let delays: Vec<_> = (1..=6).map(|attempt| backoff.delay(attempt)).collect();
+        let millis = [100, 300, 900, 2000, 2000, 2000];
+        for (delay, millis) in delays.iter().zip(millis) {
+            assert!(delay.abs_diff(Duration::from_millis(millis)) < Duration::from_micros(10));
+        }
+        // Attempt 0 is as good as the first
+        assert_eq!(backoff.delay(0), backoff.delay(1));
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+    }
+
+    #[test]
+    fn backoff_with_negative_factor() {
+        let backoff = Backoff {
+            factor: -2.0,
+            ..Default::default()
+        };
+        assert_eq!(backoff.delay(1), backoff.initial);
+        assert_eq!(backoff.delay(2), Duration::ZERO);
+        assert_eq!(backoff.delay(3), backoff.initial * 4);
+        assert_eq!(backoff.delay(1001), backoff.max);
+        assert_eq!(backoff.delay(1002), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_without_max() {
+        let backoff = Backoff {
+            max: Duration::MAX,
+            ..Default::default()
+        };
+        assert_eq!(backoff.delay(2), backoff.initial * 2);
+        assert_eq!(backoff.delay(1000), Duration::MAX);
+        assert_eq!(backoff.delay(u32::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn scaled_text_laid_out_once() {
+        let ctx = Context::default();
+        // The fonts are there after the first pass
+        let _ = ctx.run(Default::default(), |_| ());
+        let galley = ctx.fonts(|fonts| {
+            fonts.layout_no_wrap("Hi".into(), FontId::default(), Color32::WHITE)
+        });
+        let mut text = TextShape::new(Pos2::new(1.0, 2.0), galley.clone(), Color32::WHITE);
+        let transform = TSTransform::from_scaling(2.0);
+        let mut scaled_text = ScaledText::default();
+
+        scaled_text.next_pass(&ctx);
+        scale_text(&mut text, transform, &mut scaled_text, &ctx);
+        assert_eq!(text.pos, Pos2::new(2.0, 4.0));
+        assert_eq!(text.galley.size().y, galley.size().y * 2.0);
+
+        let mut layouts = 0;
+        for _ in 0..3 {
+            scaled_text.next_pass(&ctx);
+            for _ in 0..2 {
+                let again = scaled_text.get(&galley, 2.0, || {
+                    layouts += 1;
+                    galley.clone()
+                });
+                assert!(Arc::ptr_eq(&again, &text.galley));
+            }
+        }
+        assert_eq!(layouts, 0);
+
+        // Gone once it isn't painted any more
+        scaled_text.next_pass(&ctx);
+        scaled_text.next_pass(&ctx);
+        assert!(scaled_text.previous.is_empty());
+    }
+
+    /// E.g. to show how far it got
+    #[test]
+    fn placeholder_asks_about_connection() {
+        let (connector, _server) = LoopbackConnector::new();
+        let widget = ServerWidget::loopback("test", connector);
+        let ctx = Context::default();
+        let mut shown = false;
+        let _ = ctx.run(Default::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                widget.clone().show_with_placeholder(ui, |ui, _| {
+                    assert!(widget.stats(ui.ctx()).is_some());
+                    shown = true;
+                });
+            });
+        });
+        assert!(shown);
+    }
+
+    #[test]
+    fn backoff_without_initial() {
+        let backoff = Backoff {
+            initial: Duration::ZERO,
+            ..Default::default()
+        };
+        for attempt in [0, 1, 2, 10, 1000, u32::MAX] {
+            assert_eq!(backoff.delay(attempt), Duration::ZERO, "attempt {attempt}");
+        }
+    }
 }
@@ -0,0 +1,296 @@
+//! A [`ServerWidget`](crate::ServerWidget)'s end of the connection: the
+//! handshake, decompression and delta decoding.
+//!
+//! Natively, this runs on a thread of its own, so large updates don't hold up
+//! the host's frame. The UI thread only picks up the latest frame from the
+//! [`Inbox`]. On the web, it runs inline whenever the widget is shown.
+use std::sync::Arc;
+
+use log::{info, warn};
+use meterm_common::{
+    delta_encoding::Decoder,
+    egui::{
+        mutex::{Mutex, MutexGuard},
+        Context, FullOutput, Vec2,
+    },
+    hit_test::HitRegions,
+    recording::Recorder,
+    stats::Meter,
+    transport::{Frame, Transport, TransportEvent},
+    wire::Wire,
+    ClientHello, ClientToServer, ServerHello, ServerToClient,
+};
+
+use crate::{open_transport, ConnectionEvent, ServerWidget};
+
+/// What the link has for the UI thread, kept until picked up
+#[derive(Default)]
+pub(crate) struct Inbox {
+    /// Set once the transport is open and our hello went out
+    pub open: bool,
+    pub hello: Option<ServerHello>,
+    /// The latest, with the platform output of any skipped before it
+    pub frame: Option<FullOutput>,
+    pub frames_decoded: u64,
+    pub hit_regions: Option<HitRegions>,
+    pub size: Option<Vec2>,
+    pub events: Vec<ConnectionEvent>,
+    /// Why the connection ended, and the recorder to carry on with
+    pub closed: Option<(String, Option<Recorder>)>,
+    /// Both ways. Pings go out with the UI thread's input
+    pub meter: Meter,
+    pub raw_bytes_sent: u64,
+    pub raw_bytes_received: u64,
+    /// See [`ServerWidget::debug_packets`]
+    pub debug_packets: bool,
+}
+
+struct Link {
+    transport: Box<dyn Transport>,
+    wire: Wire,
+    decoder: Decoder,
+    recorder: Option<Recorder>,
+    /// Sent as soon as the transport opens
+    hello: ClientHello,
+    /// For repaints and the time
+    ctx: Context,
+    inbox: Arc<Mutex<Inbox>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+enum ToLink {
+    Input(ClientToServer),
+    /// The transport has something for us
+    Wake,
+    Close,
+}
+
+/// The UI thread's handle on a [`Link`]
+pub(crate) struct LinkHandle {
+    inbox: Arc<Mutex<Inbox>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    tx: std::sync::mpsc::Sender<ToLink>,
+    #[cfg(target_arch = "wasm32")]
+    link: Link,
+}
+
+impl LinkHandle {
+    /// Connect to the `view`'s server. The `recorder` is taken on success
+    pub fn open(
+        view: &ServerWidget,
+        ctx: &Context,
+        hello: ClientHello,
+        recorder: &mut Option<Recorder>,
+    ) -> Result<Self, String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let (tx, rx) = std::sync::mpsc::channel();
+        #[cfg(not(target_arch = "wasm32"))]
+        let transport = {
+            let tx = tx.clone();
+            open_transport(view, move || {
+                let _ = tx.send(ToLink::Wake);
+            })?
+        };
+        #[cfg(target_arch = "wasm32")]
+        let transport = {
+            let ctx = ctx.clone();
+            open_transport(view, move || ctx.request_repaint())?
+        };
+
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        let link = Link {
+            transport,
+            wire: Wire::new(view.wire_format),
+            decoder: Decoder::new(),
+            recorder: recorder.take(),
+            hello,
+            ctx: ctx.clone(),
+            inbox: inbox.clone(),
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            std::thread::Builder::new()
+                .name(format!("meterm link {}", view.addr))
+                .spawn(move || link.run(rx))
+                .map_err(|e| format!("Failed to start link thread; {e}"))?;
+            Ok(Self { inbox, tx })
+        }
+        #[cfg(target_arch = "wasm32")]
+        Ok(Self { inbox, link })
+    }
+
+    /// Give the link a chance to receive, where it doesn't run by itself
+    pub fn poll(&mut self) {
+        #[cfg(target_arch = "wasm32")]
+        self.link.receive();
+    }
+
+    pub fn send(&mut self, packet: ClientToServer) {
+        #[cfg(not(target_arch = "wasm32"))]
+        let _ = self.tx.send(ToLink::Input(packet));
+        #[cfg(target_arch = "wasm32")]
+        if let Err(error) = self.link.send(packet) {
+            self.link.close(error);
+        }
+    }
+
+    pub fn inbox(&self) -> MutexGuard<'_, Inbox> {
+        self.inbox.lock()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for LinkHandle {
+    fn drop(&mut self) {
+        let _ = self.tx.send(ToLink::Close);
+    }
+}
+
+impl Link {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run(mut self, rx: std::sync::mpsc::Receiver<ToLink>) {
+        // Transports may have queued something before the wakeup was set
+        if !self.receive() {
+            return;
+        }
+        while let Ok(msg) = rx.recv() {
+            for msg in std::iter::once(msg).chain(rx.try_iter()) {
+                match msg {
+                    ToLink::Input(packet) => {
+                        if let Err(error) = self.send(packet) {
+                            self.close(error);
+                            return;
+                        }
+                    }
+                    ToLink::Wake => (),
+                    ToLink::Close => return,
+                }
+            }
+            if !self.receive() {
+                return;
+            }
+        }
+    }
+
+    fn send(&mut self, packet: ClientToServer) -> Result<(), String> {
+        self.record(|recorder| recorder.record_input(&packet));
+        let msg = self.wire.encode(&packet).map_err(|e| format!("Serialize; {e}"))?;
+        self.send_bytes(msg);
+        Ok(())
+    }
+
+    fn send_bytes(&mut self, msg: Vec<u8>) {
+        let mut inbox = self.inbox.lock();
+        inbox.meter.sent(msg.len());
+        inbox.raw_bytes_sent = self.wire.raw_bytes_encoded();
+        drop(inbox);
+        self.transport.send(Frame::new(self.wire.format(), msg));
+    }
+
+    /// Everything the transport has. False once the connection is over
+    fn receive(&mut self) -> bool {
+        while let Some(event) = self.transport.try_recv() {
+            let result = match event {
+                TransportEvent::Opened => match self.wire.encode(&self.hello) {
+                    Ok(msg) => {
+                        self.send_bytes(msg);
+                        self.inbox.lock().open = true;
+                        Ok(())
+                    }
+                    Err(e) => Err(format!("Hello; {e}")),
+                },
+                TransportEvent::Frame(frame) => self.receive_frame(frame.bytes()),
+                TransportEvent::Error(e) => Err(e),
+                TransportEvent::Closed => Err("Connection closed".into()),
+            };
+            if let Err(error) = result {
+                self.close(error);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Tell the UI thread the connection is over, and why
+    fn close(&mut self, error: String) {
+        self.inbox.lock().closed = Some((error, self.recorder.take()));
+        self.ctx.request_repaint();
+    }
+
+    fn receive_frame(&mut self, msg: &[u8]) -> Result<(), String> {
+        let now = self.ctx.input(|i| i.time);
+        self.inbox.lock().meter.received(msg.len());
+
+        if self.inbox.lock().hello.is_none() {
+            let hello: ServerHello = self.wire.decode(msg).map_err(|e| format!("Hello; {e}"))?;
+            info!(
+                "Negotiated {:?}, codec {:?}, shape encoding {:?}",
+                self.wire.format(),
+                hello.codec,
+                hello.shape_encoding
+            );
+            match (self.hello.resume, hello.resumed) {
+                (Some(_), true) => info!("Resumed session {:?}", hello.session),
+                (Some(_), false) => info!("Session expired, starting over"),
+                (None, _) => (),
+            }
+            self.wire.apply_hello(&hello).map_err(|e| e.to_string())?;
+            let mut inbox = self.inbox.lock();
+            inbox.events.push(ConnectionEvent::Connected {
+                resumed: hello.resumed,
+            });
+            inbox.hello = Some(hello);
+            drop(inbox);
+            self.ctx.request_repaint();
+            return Ok(());
+        }
+
+        // Streaming codecs can't skip a message, so a bad one ends the connection
+        let packet: ServerToClient = self
+            .wire
+            .decode_update(msg)
+            .map_err(|e| format!("Deserialize; {e}"))?;
+        self.record(|recorder| recorder.record_output(&packet));
+
+        let mut inbox = self.inbox.lock();
+        inbox.raw_bytes_received = self.wire.raw_bytes_decoded();
+        inbox.meter.pinged(now, packet.ping, packet.pong);
+        inbox.meter.update(&packet.update, now);
+        self.decoder.debug_mode = inbox.debug_packets;
+        drop(inbox);
+
+        // The slow part, so without holding the inbox. Text is laid out on
+        // the UI thread, which has fonts for the current pixels per point
+        let full_output = self.decoder.decode(packet.update);
+
+        let mut inbox = self.inbox.lock();
+        if packet.hit_regions.is_some() {
+            inbox.hit_regions = packet.hit_regions;
+        }
+        if packet.size.is_some() {
+            inbox.size = packet.size;
+        }
+        if let Some(full_output) = full_output {
+            // Don't lose clipboard and such of frames the UI never got to
+            match &mut inbox.frame {
+                Some(frame) => frame.append(full_output),
+                None => inbox.frame = Some(full_output),
+            }
+            inbox.frames_decoded += 1;
+        }
+        drop(inbox);
+        self.ctx.request_repaint();
+
+        Ok(())
+    }
+
+    fn record(&mut self, f: impl FnOnce(&mut Recorder) -> meterm_common::Result<()>) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = f(recorder) {
+                warn!("Recording failed, stopping; {e}");
+                self.recorder = None;
+            }
+        }
+    }
+}
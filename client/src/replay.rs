@@ -0,0 +1,228 @@
+//! Playing back sessions recorded with [`ServerWidget::record_to`](crate::ServerWidget::record_to)
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use egui::{emath::TSTransform, mutex::Mutex, Id, Sense, Slider, Ui, Vec2, Widget};
+use meterm_common::{
+    delta_encoding::{Decoder, UpdateData},
+    egui::{self, Context, FullOutput},
+    recording::{RecordedMessage, RecordingReader},
+    ServerToClient,
+};
+
+use crate::{doctor_frame, paint_shapes};
+
+#[derive(Clone, Debug)]
+pub struct ReplayWidget {
+    pub path: PathBuf,
+    pub desired_size: Vec2,
+}
+
+impl ReplayWidget {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            desired_size: Vec2::new(200., 200.),
+        }
+    }
+
+    pub fn with_desired_size(mut self, size: Vec2) -> Self {
+        self.desired_size = size;
+        self
+    }
+}
+
+impl Widget for ReplayWidget {
+    fn ui(self, ui: &mut Ui) -> egui::Response {
+        let replay = ui.ctx().memory_mut(|mem| {
+            mem.data
+                .get_temp_mut_or_insert_with(Id::new(&self.path), || {
+                    Arc::new(Mutex::new(Replay::load(&self)))
+                })
+                .clone()
+        });
+
+        let mut lck = replay.lock();
+        lck.show(ui, &self)
+    }
+}
+
+enum Replay {
+    Loaded(Player),
+    Failure { error: String },
+}
+
+impl Replay {
+    fn load(view: &ReplayWidget) -> Self {
+        match Player::load(view) {
+            Ok(player) => Self::Loaded(player),
+            Err(error) => Self::Failure { error },
+        }
+    }
+
+    fn show(&mut self, ui: &mut Ui, view: &ReplayWidget) -> egui::Response {
+        match self {
+            Self::Failure { error } => {
+                ui.label(format!("Error; {error}"));
+                let resp = ui.button("Reload");
+                if resp.clicked() {
+                    *self = Self::load(view);
+                }
+                resp
+            }
+            Self::Loaded(player) => player.show(ui, view),
+        }
+    }
+}
+
+struct Player {
+    /// Only what the server sent; recorded input isn't needed to reproduce the picture
+    frames: Vec<(Duration, ServerToClient)>,
+    /// Indices into `frames` of full updates, the only places we can seek to
+    keyframes: Vec<usize>,
+    /// Index of the next frame to decode
+    next: usize,
+    /// Playback position, on the recording's clock
+    time: Duration,
+    playing: bool,
+    speed: f32,
+    decoder: Decoder,
+    latest_frame: Option<FullOutput>,
+}
+
+impl Player {
+    fn load(view: &ReplayWidget) -> Result<Self, String> {
+        let entries = RecordingReader::open(&view.path)
+            .and_then(|reader| reader.read_all())
+            .map_err(|e| format!("{}; {e}", view.path.display()))?;
+
+        let frames: Vec<(Duration, ServerToClient)> = entries
+            .into_iter()
+            .filter_map(|entry| match entry.message {
+                RecordedMessage::ServerToClient(msg) => Some((entry.time, msg)),
+                RecordedMessage::ClientToServer(_) => None,
+            })
+            .collect();
+
+        let keyframes: Vec<usize> = frames
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, msg))| matches!(msg.update, UpdateData::FullUpdate(_)))
+            .map(|(idx, _)| idx)
+            .collect();
+
+        if keyframes.is_empty() {
+            return Err(format!("{}; no keyframes", view.path.display()));
+        }
+
+        Ok(Self {
+            frames,
+            keyframes,
+            next: 0,
+            time: Duration::ZERO,
+            playing: false,
+            speed: 1.0,
+            decoder: Decoder::new(),
+            latest_frame: None,
+        })
+    }
+
+    fn show(&mut self, ui: &mut Ui, view: &ReplayWidget) -> egui::Response {
+        if self.latest_frame.is_none() {
+            self.seek(0, ui.ctx());
+        }
+
+        ui.horizontal(|ui| {
+            let label = if self.playing { "⏸ Pause" } else { "▶ Play" };
+            if ui.button(label).clicked() {
+                if !self.playing && self.next >= self.frames.len() {
+                    self.seek(0, ui.ctx());
+                }
+                self.playing = !self.playing;
+            }
+
+            if ui.button("Step").clicked() {
+                self.playing = false;
+                self.step(ui.ctx());
+            }
+
+            ui.add(
+                Slider::new(&mut self.speed, 0.1..=8.0)
+                    .logarithmic(true)
+                    .text("speed"),
+            );
+
+            let mut keyframe = self.current_keyframe();
+            let slider = Slider::new(&mut keyframe, 0..=self.keyframes.len() - 1).text("keyframe");
+            if ui.add(slider).changed() {
+                self.seek(keyframe, ui.ctx());
+            }
+
+            let total = self.frames.last().map(|(time, _)| *time).unwrap_or_default();
+            ui.label(format!(
+                "{:.1}s / {:.1}s, frame {}/{}",
+                self.time.as_secs_f32(),
+                total.as_secs_f32(),
+                self.next,
+                self.frames.len()
+            ));
+        });
+
+        if self.playing {
+            let dt = ui.input(|i| i.stable_dt) * self.speed;
+            self.time += Duration::from_secs_f32(dt);
+            while self.frames.get(self.next).is_some_and(|(time, _)| *time <= self.time) {
+                self.decode_next(ui.ctx());
+            }
+
+            if self.next >= self.frames.len() {
+                self.playing = false;
+            }
+            ui.ctx().request_repaint();
+        }
+
+        let resp = ui.allocate_response(view.desired_size, Sense::hover());
+
+        // Platform output (URLs, clipboard, ...) is deliberately not replayed
+        if let Some(full_output) = &self.latest_frame {
+            let transform = TSTransform::from_translation(resp.rect.min.to_vec2());
+            // Never scaled, so there's no text to lay out again
+            let mut scaled_text = Default::default();
+            paint_shapes(ui, resp.rect, transform, &full_output.shapes, &mut scaled_text);
+        }
+
+        resp
+    }
+
+    /// Index into `keyframes` of the last keyframe shown
+    fn current_keyframe(&self) -> usize {
+        self.keyframes
+            .iter()
+            .rposition(|&idx| idx < self.next)
+            .unwrap_or(0)
+    }
+
+    fn seek(&mut self, keyframe: usize, ctx: &Context) {
+        self.decoder = Decoder::new();
+        self.latest_frame = None;
+        self.next = self.keyframes[keyframe];
+        self.step(ctx);
+    }
+
+    fn step(&mut self, ctx: &Context) {
+        if let Some((time, _)) = self.frames.get(self.next) {
+            self.time = *time;
+            self.decode_next(ctx);
+        }
+    }
+
+    fn decode_next(&mut self, ctx: &Context) {
+        let Some((_, msg)) = self.frames.get(self.next) else {
+            return;
+        };
+        self.next += 1;
+
+        if let Some(full_output) = self.decoder.decode(msg.update.clone()) {
+            self.latest_frame = Some(doctor_frame(full_output, ctx));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use ewebsock::{WsEvent, WsMessage, WsReceiver, WsSender};
+use meterm_common::transport::{Frame, Transport, TransportEvent};
+
+pub struct WebSocket {
+    tx: WsSender,
+    rx: WsReceiver,
+}
+
+#[cfg(target_arch = "wasm32")]
+unsafe impl Send for WebSocket {}
+
+impl WebSocket {
+    pub fn connect(
+        addr: &str,
+        wakeup: impl Fn() + Send + Sync + 'static,
+    ) -> Result<Self, String> {
+        let (tx, rx) = ewebsock::connect_with_wakeup(addr, Default::default(), wakeup)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(Self { tx, rx })
+    }
+}
+
+impl Transport for WebSocket {
+    fn send(&mut self, frame: Frame) {
+        self.tx.send(match frame {
+            Frame::Binary(bytes) => WsMessage::Binary(bytes),
+            Frame::Text(text) => WsMessage::Text(text),
+        });
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        loop {
+            return Some(match self.rx.try_recv()? {
+                WsEvent::Opened => TransportEvent::Opened,
+                WsEvent::Message(WsMessage::Binary(bytes)) => {
+                    TransportEvent::Frame(Frame::Binary(bytes))
+                }
+                WsEvent::Message(WsMessage::Text(text)) => TransportEvent::Frame(Frame::Text(text)),
+                WsEvent::Message(_) => continue,
+                WsEvent::Error(e) => TransportEvent::Error(e),
+                WsEvent::Closed => TransportEvent::Closed,
+            });
+        }
+    }
+}
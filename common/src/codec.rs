@@ -0,0 +1,423 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "zstd")]
+use std::io::Write;
+
+/// Compression applied to every serialized message on the wire.
+///
+/// Each compressed message starts with a tag byte naming the codec, so the
+/// receiving end can always decompress regardless of what was negotiated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Send serialized bytes as-is
+    None,
+    /// lz4 on each message by itself. Fast, and a good deal smaller
+    Lz4,
+    /// zstd at the given level (1..=22). Slower, but smaller
+    Zstd { level: i32 },
+    /// zstd using the built-in dictionary trained on egui shape streams
+    ZstdDict { level: i32 },
+    /// lz4 using the most recent frames as a dictionary, so repeated
+    /// structure between frames is exploited. Stateful; see [`Codec::is_streaming`]
+    Lz4Stream,
+    /// zstd with a single compression context whose window spans previous frames. Stateful
+    ZstdStream { level: i32 },
+}
+
+const TAG_NONE: u8 = 0;
+const TAG_LZ4: u8 = 1;
+const TAG_ZSTD: u8 = 2;
+const TAG_ZSTD_DICT: u8 = 3;
+const TAG_LZ4_STREAM: u8 = 4;
+const TAG_ZSTD_STREAM: u8 = 5;
+
+/// How much of the previous frames the lz4 stream may refer back to
+const LZ4_WINDOW_SIZE: usize = 64 * 1024;
+
+/// Longest message once decompressed. The sizes in a message are up to the
+/// peer, so nothing bigger is allocated on their word
+pub const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// Dictionary trained on the shipped examples (see `examples/codec_bench.rs` in the server crate)
+#[cfg(feature = "zstd")]
+pub static EGUI_SHAPES_DICTIONARY: &[u8] = include_bytes!("egui_shapes.zdict");
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::Lz4
+    }
+}
+
+impl Codec {
+    /// Codecs compiled into this build, most preferred first
+    pub fn supported() -> Vec<Codec> {
+        let mut codecs = vec![];
+        #[cfg(feature = "zstd")]
+        codecs.extend([
+            Codec::ZstdStream { level: 3 },
+            Codec::ZstdDict { level: 3 },
+            Codec::Zstd { level: 3 },
+        ]);
+        codecs.extend([Codec::Lz4Stream, Codec::Lz4, Codec::None]);
+        codecs
+    }
+
+    /// Whether this build is able to compress and decompress this codec
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Self::None | Self::Lz4 | Self::Lz4Stream => true,
+            Self::Zstd { .. } | Self::ZstdDict { .. } | Self::ZstdStream { .. } => {
+                cfg!(feature = "zstd")
+            }
+        }
+    }
+
+    /// Streaming codecs carry state from one message to the next, so every
+    /// message must be decompressed exactly once and in the order it was sent.
+    pub fn is_streaming(&self) -> bool {
+        matches!(self, Self::Lz4Stream | Self::ZstdStream { .. })
+    }
+
+    /// Picks the first of the server's `preferred` codecs which the client
+    /// also understands. Compression levels only matter to the compressing
+    /// side, so they are ignored when matching.
+    pub fn negotiate(preferred: &[Codec], client_supported: &[Codec]) -> Codec {
+        preferred
+            .iter()
+            .find(|codec| {
+                codec.is_supported() && client_supported.iter().any(|c| c.tag() == codec.tag())
+            })
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Self::None => TAG_NONE,
+            Self::Lz4 => TAG_LZ4,
+            Self::Zstd { .. } => TAG_ZSTD,
+            Self::ZstdDict { .. } => TAG_ZSTD_DICT,
+            Self::Lz4Stream => TAG_LZ4_STREAM,
+            Self::ZstdStream { .. } => TAG_ZSTD_STREAM,
+        }
+    }
+}
+
+/// Compresses outgoing messages for a single connection
+pub struct Compressor {
+    codec: Codec,
+    lz4_window: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    #[cfg(feature = "zstd")]
+    zstd_stream: Option<zstd::stream::write::Encoder<'static, Vec<u8>>>,
+}
+
+/// Decompresses incoming messages for a single connection
+#[derive(Default)]
+pub struct Decompressor {
+    lz4_window: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+    #[cfg(feature = "zstd")]
+    zstd_dict: Option<zstd::bulk::Decompressor<'static>>,
+    #[cfg(feature = "zstd")]
+    zstd_stream: Option<zstd::stream::write::Decoder<'static, Capped>>,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new(Codec::default()).unwrap()
+    }
+}
+
+impl Compressor {
+    pub fn new(codec: Codec) -> Result<Self> {
+        if !codec.is_supported() {
+            return Err(Error::Protocol(format!(
+                "Codec {codec:?} is not supported by this build"
+            )));
+        }
+
+        Ok(Self {
+            codec,
+            lz4_window: vec![],
+            #[cfg(feature = "zstd")]
+            zstd: match codec {
+                Codec::Zstd { level } => Some(zstd::bulk::Compressor::new(level)?),
+                Codec::ZstdDict { level } => Some(zstd::bulk::Compressor::with_dictionary(
+                    level,
+                    EGUI_SHAPES_DICTIONARY,
+                )?),
+                _ => None,
+            },
+            #[cfg(feature = "zstd")]
+            zstd_stream: match codec {
+                Codec::ZstdStream { level } => {
+                    Some(zstd::stream::write::Encoder::new(Vec::new(), level)?)
+                }
+                _ => None,
+            },
+        })
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Refuses messages longer than [`MAX_MESSAGE_LEN`], which the peer wouldn't take
+    pub fn compress(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        check_len(bytes.len())?;
+        let mut out = vec![self.codec.tag()];
+        match self.codec {
+            Codec::None => out.extend_from_slice(bytes),
+            Codec::Lz4 => out.extend(lz4_flex::compress_prepend_size(bytes)),
+            Codec::Lz4Stream => {
+                out.extend(lz4_flex::block::compress_prepend_size_with_dict(
+                    bytes,
+                    &self.lz4_window,
+                ));
+                push_window(&mut self.lz4_window, bytes);
+            }
+            #[cfg(feature = "zstd")]
+            Codec::ZstdStream { .. } => {
+                // Flushing ends the block without ending the frame, so the
+                // decoder can emit everything written so far
+                let zstd = self.zstd_stream.as_mut().expect("zstd stream");
+                zstd.write_all(bytes)?;
+                zstd.flush()?;
+                out.append(zstd.get_mut());
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd { .. } | Codec::ZstdDict { .. } => {
+                let zstd = self.zstd.as_mut().expect("zstd compressor");
+                out.extend((bytes.len() as u32).to_le_bytes());
+                out.extend(zstd.compress(bytes)?);
+            }
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd { .. } | Codec::ZstdDict { .. } | Codec::ZstdStream { .. } => {
+                unreachable!()
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decompress(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let Some((&tag, body)) = bytes.split_first() else {
+            return Err(Error::Protocol("Empty message".into()));
+        };
+
+        match tag {
+            TAG_NONE => Ok(body.to_vec()),
+            TAG_LZ4 => {
+                check_len(prepended_len(body)?)?;
+                Ok(lz4_flex::decompress_size_prepended(body)?)
+            }
+            TAG_LZ4_STREAM => {
+                check_len(prepended_len(body)?)?;
+                let bytes =
+                    lz4_flex::block::decompress_size_prepended_with_dict(body, &self.lz4_window)?;
+                push_window(&mut self.lz4_window, &bytes);
+                Ok(bytes)
+            }
+            #[cfg(feature = "zstd")]
+            TAG_ZSTD_STREAM => {
+                let zstd = match &mut self.zstd_stream {
+                    Some(zstd) => zstd,
+                    none => none.insert(zstd::stream::write::Decoder::new(Capped::default())?),
+                };
+                zstd.write_all(body)?;
+                zstd.flush()?;
+                Ok(std::mem::take(&mut zstd.get_mut().0))
+            }
+            #[cfg(feature = "zstd")]
+            TAG_ZSTD | TAG_ZSTD_DICT => {
+                let len = prepended_len(body)?;
+                check_len(len)?;
+                let body = &body[4..];
+
+                let zstd = if tag == TAG_ZSTD {
+                    &mut self.zstd
+                } else {
+                    &mut self.zstd_dict
+                };
+
+                if zstd.is_none() {
+                    *zstd = Some(if tag == TAG_ZSTD {
+                        zstd::bulk::Decompressor::new()?
+                    } else {
+                        zstd::bulk::Decompressor::with_dictionary(EGUI_SHAPES_DICTIONARY)?
+                    });
+                }
+
+                Ok(zstd.as_mut().unwrap().decompress(body, len)?)
+            }
+            _ => Err(Error::Protocol(format!("Unsupported codec tag {tag}"))),
+        }
+    }
+}
+
+/// The size lz4 and bulk zstd messages start with
+fn prepended_len(body: &[u8]) -> Result<usize> {
+    match body.first_chunk::<4>() {
+        Some(len) => Ok(u32::from_le_bytes(*len) as usize),
+        None => Err(Error::Protocol("Truncated message".into())),
+    }
+}
+
+fn check_len(len: usize) -> Result<()> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::Protocol(format!(
+            "Message of {len} bytes is too long"
+        )));
+    }
+    Ok(())
+}
+
+/// Output of the zstd stream, which could otherwise grow without bound from
+/// a small message
+#[cfg(feature = "zstd")]
+#[derive(Default)]
+struct Capped(Vec<u8>);
+
+#[cfg(feature = "zstd")]
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.0.len() + buf.len() > MAX_MESSAGE_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Message too long",
+            ));
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn push_window(window: &mut Vec<u8>, bytes: &[u8]) {
+    window.extend_from_slice(bytes);
+    if window.len() > LZ4_WINDOW_SIZE {
+        window.drain(..window.len() - LZ4_WINDOW_SIZE);
+    }
+}
+
+/// Trains a zstd dictionary from a set of serialized (uncompressed) messages
+#[cfg(feature = "zstd")]
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noise on its own, but only a few bytes change from one to the next,
+    /// like successive frames of a UI
+    fn frames() -> Vec<Vec<u8>> {
+        let mut seed = 1u32;
+        let mut frame: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 24) as u8
+            })
+            .collect();
+        (0..8)
+            .map(|i| {
+                frame[i * 100] = frame[i * 100].wrapping_add(1);
+                frame.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for codec in Codec::supported() {
+            let mut compressor = Compressor::new(codec).unwrap();
+            let mut decompressor = Decompressor::new();
+            for frame in [vec![], vec![42], frames().remove(0)] {
+                let compressed = compressor.compress(&frame).unwrap();
+                assert_eq!(
+                    decompressor.decompress(&compressed).unwrap(),
+                    frame,
+                    "{codec:?}"
+                );
+            }
+        }
+    }
+
+    /// Each message must be decompressed in turn, with both ends keeping state
+    #[test]
+    fn streaming_lockstep() {
+        let streaming = Codec::supported()
+            .into_iter()
+            .filter(Codec::is_streaming)
+            .collect::<Vec<_>>();
+        assert!(streaming.contains(&Codec::Lz4Stream));
+        #[cfg(feature = "zstd")]
+        assert!(streaming.contains(&Codec::ZstdStream { level: 3 }));
+
+        for codec in streaming {
+            let mut compressor = Compressor::new(codec).unwrap();
+            let mut decompressor = Decompressor::new();
+            let mut sizes = vec![];
+            for frame in frames() {
+                let compressed = compressor.compress(&frame).unwrap();
+                sizes.push(compressed.len());
+                assert_eq!(
+                    decompressor.decompress(&compressed).unwrap(),
+                    frame,
+                    "{codec:?}"
+                );
+            }
+            // Later frames refer back to earlier ones
+            assert!(
+                sizes[1..].iter().all(|&size| size < sizes[0] / 4),
+                "{codec:?} {sizes:?}"
+            );
+        }
+    }
+
+    /// Sizes in the message aren't taken at their word
+    #[test]
+    fn rejects_too_long() {
+        let huge = (MAX_MESSAGE_LEN as u32 + 1).to_le_bytes();
+        for tag in [TAG_LZ4, TAG_LZ4_STREAM, TAG_ZSTD, TAG_ZSTD_DICT] {
+            let mut msg = vec![tag];
+            msg.extend(huge);
+            msg.extend([0; 16]);
+            assert!(Decompressor::new().decompress(&msg).is_err(), "tag {tag}");
+        }
+
+        let mut compressor = Compressor::new(Codec::Lz4).unwrap();
+        assert!(compressor.compress(&vec![0; MAX_MESSAGE_LEN + 1]).is_err());
+    }
+
+    /// A small message which would decompress to far too much
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn rejects_zstd_stream_bomb() {
+        use std::io::Read;
+
+        let zeros = std::io::repeat(0).take(MAX_MESSAGE_LEN as u64 + 1);
+        let mut msg = vec![TAG_ZSTD_STREAM];
+        msg.extend(zstd::stream::encode_all(zeros, 3).unwrap());
+        assert!(msg.len() < 1 << 20);
+        assert!(Decompressor::new().decompress(&msg).is_err());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut decompressor = Decompressor::new();
+        assert!(decompressor.decompress(&[]).is_err());
+        assert!(decompressor.decompress(&[0xff, 1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,673 @@
+//! Compact wire representation of [`ServerToClient`] updates.
+//!
+//! Positions are quantized to fixed point relative to the shape's clip rect,
+//! all integers (including delta encoding references) are varint encoded and
+//! colors are packed into a single `u32`. Shapes without a compact form are
+//! sent as-is.
+use std::cell::Cell;
+
+use bincode::Options;
+use egui::{
+    epaint::{
+        CircleShape, ClippedShape, ColorMode, CornerRadius, PathShape, PathStroke, RectShape,
+        StrokeKind, TextShape,
+    },
+    Color32, FullOutput, Pos2, Rect, Shape, Stroke, Vec2,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    delta_encoding::{PartialUpdate, UpdateData},
+    hit_test::HitRegions,
+    Result, ServerToClient,
+};
+
+/// Subdivisions per point. Positions are off by at most half of one subdivision
+pub const QUANTIZATION: f32 = 8.0;
+
+/// Worst case position error introduced by quantization, in points
+pub const MAX_POSITION_ERROR: f32 = 0.5 / QUANTIZATION;
+
+/// Shapes nested deeper than this in [`Shape::Vec`]s are refused, rather than
+/// recursing until the stack runs out
+pub const MAX_NESTING: usize = 64;
+
+/// Which representation of shapes is used on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShapeEncoding {
+    /// Plain serialized egui structs
+    #[default]
+    Standard,
+    /// See [`crate::compact`]
+    Compact,
+}
+
+pub fn serialize(val: &ServerToClient) -> Result<Vec<u8>> {
+    Ok(bincode::DefaultOptions::new().serialize(&CompactServerToClient::from(val))?)
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<ServerToClient> {
+    let compact: CompactServerToClient = bincode::DefaultOptions::new().deserialize(bytes)?;
+    Ok(compact.into())
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactServerToClient {
+    full: bool,
+    /// Everything but the shapes
+    output: FullOutput,
+    shapes: Vec<CompactPartial>,
+    hit_regions: Option<HitRegions>,
+    size: Option<Vec2>,
+    ping: Option<u32>,
+    pong: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CompactPartial {
+    Reference(usize),
+    Shape(CompactClippedShape),
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactClippedShape {
+    clip_rect: QRect,
+    shape: CompactShape,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CompactShape {
+    Noop,
+    Vec(#[serde(deserialize_with = "deserialize_nested")] Vec<CompactShape>),
+    Circle {
+        center: QPos,
+        radius: i32,
+        fill: u32,
+        stroke: CompactStroke,
+    },
+    LineSegment {
+        points: [QPos; 2],
+        stroke: CompactStroke,
+    },
+    Rect {
+        rect: QRect,
+        corner_radius: CornerRadius,
+        fill: u32,
+        stroke: CompactStroke,
+        stroke_kind: StrokeKind,
+        round_to_pixels: Option<bool>,
+        blur_width: f32,
+    },
+    Path {
+        /// Each point is relative to the one before it
+        points: Vec<QPos>,
+        closed: bool,
+        fill: u32,
+        stroke: CompactStroke,
+        stroke_kind: StrokeKind,
+    },
+    /// The galley is already relative to `pos`, so only `pos` is quantized
+    Text { pos: QPos, shape: TextShape },
+    Other(Shape),
+}
+
+/// Position in fixed point, relative to an origin
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct QPos(i32, i32);
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct QRect(QPos, QPos);
+
+#[derive(Serialize, Deserialize)]
+struct CompactStroke {
+    width: i32,
+    color: u32,
+}
+
+impl From<&ServerToClient> for CompactServerToClient {
+    fn from(val: &ServerToClient) -> Self {
+        let (full, output, shapes) = match &val.update {
+            UpdateData::FullUpdate(output) => (
+                true,
+                output,
+                output
+                    .shapes
+                    .iter()
+                    .map(|shape| CompactPartial::Shape(shape.into()))
+                    .collect(),
+            ),
+            UpdateData::Partial(output, partials) => (
+                false,
+                output,
+                partials
+                    .iter()
+                    .map(|partial| match partial {
+                        PartialUpdate::Reference(index) => CompactPartial::Reference(*index),
+                        PartialUpdate::Shape(shape) => CompactPartial::Shape(shape.into()),
+                    })
+                    .collect(),
+            ),
+        };
+
+        let output = FullOutput {
+            platform_output: output.platform_output.clone(),
+            textures_delta: output.textures_delta.clone(),
+            shapes: vec![],
+            pixels_per_point: output.pixels_per_point,
+            viewport_output: output.viewport_output.clone(),
+        };
+
+        Self {
+            full,
+            output,
+            shapes,
+            hit_regions: val.hit_regions.clone(),
+            size: val.size,
+            ping: val.ping,
+            pong: val.pong,
+        }
+    }
+}
+
+impl From<CompactServerToClient> for ServerToClient {
+    fn from(val: CompactServerToClient) -> Self {
+        let CompactServerToClient {
+            full,
+            mut output,
+            shapes,
+            hit_regions,
+            size,
+            ping,
+            pong,
+        } = val;
+
+        let update = if full {
+            output.shapes = shapes
+                .into_iter()
+                .filter_map(|partial| match partial {
+                    CompactPartial::Shape(shape) => Some(shape.into()),
+                    CompactPartial::Reference(_) => None,
+                })
+                .collect();
+            UpdateData::FullUpdate(output)
+        } else {
+            let partials = shapes
+                .into_iter()
+                .map(|partial| match partial {
+                    CompactPartial::Reference(index) => PartialUpdate::Reference(index),
+                    CompactPartial::Shape(shape) => PartialUpdate::Shape(shape.into()),
+                })
+                .collect();
+            UpdateData::Partial(output, partials)
+        };
+
+        ServerToClient {
+            update,
+            hit_regions,
+            size,
+            ping,
+            pong,
+        }
+    }
+}
+
+impl From<&ClippedShape> for CompactClippedShape {
+    fn from(val: &ClippedShape) -> Self {
+        let clip_rect = QRect::new(val.clip_rect, Pos2::ZERO);
+        let origin = clip_rect.origin();
+        Self {
+            clip_rect,
+            shape: CompactShape::new(&val.shape, origin),
+        }
+    }
+}
+
+impl From<CompactClippedShape> for ClippedShape {
+    fn from(val: CompactClippedShape) -> Self {
+        let origin = val.clip_rect.origin();
+        ClippedShape {
+            clip_rect: val.clip_rect.get(Pos2::ZERO),
+            shape: val.shape.get(origin),
+        }
+    }
+}
+
+impl CompactShape {
+    fn new(shape: &Shape, origin: Pos2) -> Self {
+        match shape {
+            Shape::Noop => Self::Noop,
+            Shape::Vec(shapes) => {
+                Self::Vec(shapes.iter().map(|shape| Self::new(shape, origin)).collect())
+            }
+            Shape::Circle(circle) => Self::Circle {
+                center: QPos::new(circle.center, origin),
+                radius: quantize(circle.radius),
+                fill: pack_color(circle.fill),
+                stroke: circle.stroke.into(),
+            },
+            Shape::LineSegment { points, stroke } => Self::LineSegment {
+                points: points.map(|point| QPos::new(point, origin)),
+                stroke: (*stroke).into(),
+            },
+            Shape::Rect(rect) if rect.brush.is_none() => Self::Rect {
+                rect: QRect::new(rect.rect, origin),
+                corner_radius: rect.corner_radius,
+                fill: pack_color(rect.fill),
+                stroke: rect.stroke.into(),
+                stroke_kind: rect.stroke_kind,
+                round_to_pixels: rect.round_to_pixels,
+                blur_width: rect.blur_width,
+            },
+            Shape::Path(PathShape {
+                points,
+                closed,
+                fill,
+                stroke:
+                    PathStroke {
+                        width,
+                        color: ColorMode::Solid(color),
+                        kind,
+                    },
+            }) => {
+                let mut last = QPos(0, 0);
+                let points = points
+                    .iter()
+                    .map(|&point| {
+                        let pos = QPos::new(point, origin);
+                        // Infinite points are as far apart as it gets
+                        let delta = QPos(pos.0.wrapping_sub(last.0), pos.1.wrapping_sub(last.1));
+                        last = pos;
+                        delta
+                    })
+                    .collect();
+
+                Self::Path {
+                    points,
+                    closed: *closed,
+                    fill: pack_color(*fill),
+                    stroke: Stroke::new(*width, *color).into(),
+                    stroke_kind: *kind,
+                }
+            }
+            Shape::Text(text) => Self::Text {
+                pos: QPos::new(text.pos, origin),
+                shape: TextShape {
+                    pos: Pos2::ZERO,
+                    ..text.clone()
+                },
+            },
+            other => Self::Other(other.clone()),
+        }
+    }
+
+    fn get(self, origin: Pos2) -> Shape {
+        match self {
+            Self::Noop => Shape::Noop,
+            Self::Vec(shapes) => {
+                Shape::Vec(shapes.into_iter().map(|shape| shape.get(origin)).collect())
+            }
+            Self::Circle {
+                center,
+                radius,
+                fill,
+                stroke,
+            } => Shape::Circle(CircleShape {
+                center: center.get(origin),
+                radius: dequantize(radius),
+                fill: unpack_color(fill),
+                stroke: stroke.into(),
+            }),
+            Self::LineSegment { points, stroke } => Shape::LineSegment {
+                points: points.map(|point| point.get(origin)),
+                stroke: stroke.into(),
+            },
+            Self::Rect {
+                rect,
+                corner_radius,
+                fill,
+                stroke,
+                stroke_kind,
+                round_to_pixels,
+                blur_width,
+            } => Shape::Rect(RectShape {
+                rect: rect.get(origin),
+                corner_radius,
+                fill: unpack_color(fill),
+                stroke: stroke.into(),
+                stroke_kind,
+                round_to_pixels,
+                blur_width,
+                brush: None,
+            }),
+            Self::Path {
+                points,
+                closed,
+                fill,
+                stroke,
+                stroke_kind,
+            } => {
+                let mut last = QPos(0, 0);
+                let points = points
+                    .into_iter()
+                    .map(|delta| {
+                        last = QPos(last.0.wrapping_add(delta.0), last.1.wrapping_add(delta.1));
+                        last.get(origin)
+                    })
+                    .collect();
+
+                let stroke: Stroke = stroke.into();
+                Shape::Path(PathShape {
+                    points,
+                    closed,
+                    fill: unpack_color(fill),
+                    stroke: PathStroke {
+                        width: stroke.width,
+                        color: ColorMode::Solid(stroke.color),
+                        kind: stroke_kind,
+                    },
+                })
+            }
+            Self::Text { pos, shape } => Shape::Text(TextShape {
+                pos: pos.get(origin),
+                ..shape
+            }),
+            Self::Other(shape) => shape,
+        }
+    }
+}
+
+impl QPos {
+    fn new(pos: Pos2, origin: Pos2) -> Self {
+        Self(quantize(pos.x - origin.x), quantize(pos.y - origin.y))
+    }
+
+    fn get(self, origin: Pos2) -> Pos2 {
+        Pos2::new(dequantize(self.0), dequantize(self.1)) + origin.to_vec2()
+    }
+}
+
+impl QRect {
+    fn new(rect: Rect, origin: Pos2) -> Self {
+        Self(QPos::new(rect.min, origin), QPos::new(rect.max, origin))
+    }
+
+    fn get(self, origin: Pos2) -> Rect {
+        Rect::from_min_max(self.0.get(origin), self.1.get(origin))
+    }
+
+    /// Shapes are positioned relative to the (quantized) top left of their clip rect
+    fn origin(&self) -> Pos2 {
+        let min = self.0.get(Pos2::ZERO);
+        if min.is_finite() {
+            min
+        } else {
+            Pos2::ZERO
+        }
+    }
+}
+
+impl From<Stroke> for CompactStroke {
+    fn from(stroke: Stroke) -> Self {
+        Self {
+            width: quantize(stroke.width),
+            color: pack_color(stroke.color),
+        }
+    }
+}
+
+impl From<CompactStroke> for Stroke {
+    fn from(stroke: CompactStroke) -> Self {
+        Stroke::new(dequantize(stroke.width), unpack_color(stroke.color))
+    }
+}
+
+thread_local! {
+    /// Of the [`CompactShape::Vec`] being deserialized
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+fn deserialize_nested<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<CompactShape>, D::Error> {
+    /// Back out even if deserializing fails
+    struct Level;
+    impl Drop for Level {
+        fn drop(&mut self) {
+            NESTING.set(NESTING.get() - 1);
+        }
+    }
+
+    if NESTING.get() >= MAX_NESTING {
+        return Err(serde::de::Error::custom("Shapes nested too deeply"));
+    }
+    NESTING.set(NESTING.get() + 1);
+    let _level = Level;
+    Vec::deserialize(deserializer)
+}
+
+/// Infinite values (e.g. `Rect::EVERYTHING`) saturate, and are restored by [`dequantize`]
+fn quantize(value: f32) -> i32 {
+    (value * QUANTIZATION).round() as i32
+}
+
+fn dequantize(value: i32) -> f32 {
+    match value {
+        i32::MAX => f32::INFINITY,
+        i32::MIN => f32::NEG_INFINITY,
+        value => value as f32 / QUANTIZATION,
+    }
+}
+
+/// Transparent (the most common fill) packs to zero, which is a single byte as a varint
+fn pack_color(color: Color32) -> u32 {
+    u32::from_le_bytes(color.to_array())
+}
+
+fn unpack_color(packed: u32) -> Color32 {
+    let [r, g, b, a] = packed.to_le_bytes();
+    Color32::from_rgba_premultiplied(r, g, b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{
+        epaint::text::{FontDefinitions, Fonts},
+        FontId,
+    };
+
+    use super::*;
+
+    fn round_trip(shapes: Vec<ClippedShape>) -> Vec<ClippedShape> {
+        let packet = ServerToClient {
+            update: UpdateData::FullUpdate(FullOutput {
+                shapes,
+                ..Default::default()
+            }),
+            hit_regions: None,
+            size: None,
+            ping: None,
+            pong: None,
+        };
+        match deserialize(&serialize(&packet).unwrap()).unwrap().update {
+            UpdateData::FullUpdate(output) => output.shapes,
+            UpdateData::Partial(..) => panic!("Full update came back partial"),
+        }
+    }
+
+    fn assert_pos_close(expected: Pos2, actual: Pos2) {
+        // A little slack for float error
+        let tolerance = MAX_POSITION_ERROR + 1e-4;
+        assert!(
+            (expected.x - actual.x).abs() <= tolerance && (expected.y - actual.y).abs() <= tolerance,
+            "{expected:?} vs {actual:?}"
+        );
+    }
+
+    fn assert_rect_close(expected: Rect, actual: Rect) {
+        assert_pos_close(expected.min, actual.min);
+        assert_pos_close(expected.max, actual.max);
+    }
+
+    fn clip() -> Rect {
+        Rect::from_min_max(Pos2::new(3.3, 7.77), Pos2::new(640.1, 480.9))
+    }
+
+    /// Widths on the quantization grid come back exactly
+    fn stroke() -> Stroke {
+        Stroke::new(1.5, Color32::from_rgba_premultiplied(10, 20, 30, 40))
+    }
+
+    #[test]
+    fn circle() {
+        let circle = CircleShape {
+            center: Pos2::new(100.31, 50.07),
+            radius: 12.34,
+            fill: Color32::RED,
+            stroke: stroke(),
+        };
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: Shape::Circle(circle),
+        }]);
+
+        assert_rect_close(clip(), shapes[0].clip_rect);
+        let Shape::Circle(actual) = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        assert_pos_close(circle.center, actual.center);
+        assert!((circle.radius - actual.radius).abs() <= MAX_POSITION_ERROR + 1e-4);
+        assert_eq!(circle.fill, actual.fill);
+        assert_eq!(circle.stroke, actual.stroke);
+    }
+
+    #[test]
+    fn rect_with_rounding_and_stroke() {
+        let rect = RectShape::new(
+            Rect::from_min_size(Pos2::new(20.06, 30.94), Vec2::new(99.99, 10.01)),
+            CornerRadius::same(6),
+            Color32::from_gray(40),
+            stroke(),
+            StrokeKind::Outside,
+        );
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: Shape::Rect(rect.clone()),
+        }]);
+
+        let Shape::Rect(actual) = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        assert_rect_close(rect.rect, actual.rect);
+        assert_eq!(rect.corner_radius, actual.corner_radius);
+        assert_eq!(rect.fill, actual.fill);
+        assert_eq!(rect.stroke, actual.stroke);
+        assert_eq!(rect.stroke_kind, actual.stroke_kind);
+    }
+
+    #[test]
+    fn path() {
+        let points = vec![
+            Pos2::new(10.01, 10.02),
+            Pos2::new(200.55, 12.3),
+            Pos2::new(150.9, 300.49),
+            Pos2::new(-20.13, 41.0),
+        ];
+        let path = PathShape::convex_polygon(points.clone(), Color32::BLUE, stroke());
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: Shape::Path(path.clone()),
+        }]);
+
+        let Shape::Path(actual) = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        // Points are deltas on the wire, so errors mustn't add up along the path
+        assert_eq!(points.len(), actual.points.len());
+        for (&expected, &actual) in points.iter().zip(&actual.points) {
+            assert_pos_close(expected, actual);
+        }
+        assert!(actual.closed);
+        assert_eq!(path.fill, actual.fill);
+        assert_eq!(path.stroke, actual.stroke);
+    }
+
+    #[test]
+    fn text() {
+        let fonts = Fonts::new(1.0, 1024, FontDefinitions::default());
+        let galley = fonts.layout_no_wrap("Hello".into(), FontId::default(), Color32::WHITE);
+        let text = TextShape::new(Pos2::new(40.44, 60.66), galley, Color32::GREEN);
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: Shape::Text(text.clone()),
+        }]);
+
+        let Shape::Text(actual) = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        assert_pos_close(text.pos, actual.pos);
+        assert_eq!(text.galley.text(), actual.galley.text());
+        assert_eq!(text.galley.rect, actual.galley.rect);
+        assert_eq!(text.fallback_color, actual.fallback_color);
+    }
+
+    #[test]
+    fn unclipped() {
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: Rect::EVERYTHING,
+            shape: Shape::LineSegment {
+                points: [Pos2::new(-5.55, 1.01), Pos2::new(1e4, 2e4)],
+                stroke: stroke(),
+            },
+        }]);
+
+        assert_eq!(Rect::EVERYTHING, shapes[0].clip_rect);
+        let Shape::LineSegment { points, stroke: actual } = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        assert_pos_close(Pos2::new(-5.55, 1.01), points[0]);
+        assert_pos_close(Pos2::new(1e4, 2e4), points[1]);
+        assert_eq!(stroke(), *actual);
+    }
+
+    #[test]
+    fn path_to_infinity() {
+        let points = vec![
+            Pos2::new(f32::NEG_INFINITY, 3.0),
+            Pos2::new(f32::INFINITY, f32::NEG_INFINITY),
+            Pos2::new(1.0, f32::INFINITY),
+        ];
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: Shape::Path(PathShape::line(points.clone(), stroke())),
+        }]);
+        let Shape::Path(path) = &shapes[0].shape else {
+            panic!("{:?}", shapes[0].shape);
+        };
+        assert_eq!(path.points, points);
+    }
+
+    #[test]
+    fn too_deeply_nested() {
+        let nested = |depth| (0..depth).fold(Shape::Noop, |shape, _| Shape::Vec(vec![shape]));
+        let shapes = round_trip(vec![ClippedShape {
+            clip_rect: clip(),
+            shape: nested(MAX_NESTING),
+        }]);
+        assert_eq!(shapes[0].shape, nested(MAX_NESTING));
+
+        // As a peer might send, far deeper than the stack would take
+        let mut bytes = vec![];
+        for _ in 0..1_000_000 {
+            // The `Vec` variant, holding one shape
+            bytes.extend([1, 1]);
+        }
+        bytes.push(0);
+        let options = bincode::DefaultOptions::new();
+        assert!(options.deserialize::<CompactShape>(&bytes).is_err());
+        // And no harm done to the next message
+        let bytes = options
+            .serialize(&CompactShape::new(&nested(3), Pos2::ZERO))
+            .unwrap();
+        assert!(options.deserialize::<CompactShape>(&bytes).is_ok());
+    }
+}
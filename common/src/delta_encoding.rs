@@ -54,6 +54,11 @@ impl Encoder {
         self.encode_manual_partial(data, do_partial_update)
     }
 
+    /// Make the next call to [`Encoder::encode`] send a full update
+    pub fn request_keyframe(&mut self) {
+        self.memory.clear();
+    }
+
     pub fn encode_manual_partial(&mut self, data: &FullOutput, partial: bool) -> UpdateData {
         if partial {
             let mut data = data.clone();
@@ -0,0 +1,52 @@
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong turning bytes into messages and back
+#[derive(Debug)]
+pub enum Error {
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    Lz4(lz4_flex::block::DecompressError),
+    /// The peer sent something which doesn't follow the protocol
+    Protocol(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bincode(e) => write!(f, "bincode: {e}"),
+            Self::Json(e) => write!(f, "json: {e}"),
+            Self::Io(e) => write!(f, "io: {e}"),
+            Self::Lz4(e) => write!(f, "lz4: {e}"),
+            Self::Protocol(e) => write!(f, "protocol: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Self::Bincode(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<lz4_flex::block::DecompressError> for Error {
+    fn from(e: lz4_flex::block::DecompressError) -> Self {
+        Self::Lz4(e)
+    }
+}
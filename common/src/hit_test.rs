@@ -0,0 +1,110 @@
+//! Where the server's widgets are, so a client can show hover feedback right
+//! away instead of a round trip later.
+//!
+//! Sent alongside updates when asked for in [`crate::ClientHello::hit_regions`],
+//! and only when they changed. The client highlights the region under its
+//! pointer until a frame from the server catches up with it.
+use egui::{epaint::CornerRadius, CursorIcon, Pos2, Rect, Stroke};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HitRegions {
+    /// Back to front, so the last region containing a point is the one hit
+    pub regions: Vec<HitRegion>,
+    /// How the server draws hovered widgets
+    pub visuals: HoverVisuals,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HitRegion {
+    /// In the server's screen coordinates
+    pub rect: Rect,
+    /// Whether the widget lights up when hovered. Regions which don't are
+    /// still there to cover whatever lies below them
+    pub highlight: bool,
+    /// The cursor the server showed when this was last hovered, if it ever was
+    pub cursor: Option<CursorIcon>,
+    /// Whether the server has this hovered at the moment
+    pub hovered: bool,
+}
+
+/// The parts of `egui::style::WidgetVisuals` needed to outline a hovered widget
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HoverVisuals {
+    pub stroke: Stroke,
+    pub corner_radius: CornerRadius,
+    pub expansion: f32,
+}
+
+impl HitRegions {
+    /// Index of the topmost region at `pos`
+    pub fn at(&self, pos: Pos2) -> Option<usize> {
+        self.regions
+            .iter()
+            .rposition(|region| region.rect.contains(pos))
+    }
+
+    /// Index of the region the server has hovered
+    pub fn hovered(&self) -> Option<usize> {
+        self.regions.iter().rposition(|region| region.hovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::pos2;
+
+    use super::*;
+
+    fn region(min: (f32, f32), max: (f32, f32)) -> HitRegion {
+        HitRegion {
+            rect: Rect::from_min_max(pos2(min.0, min.1), pos2(max.0, max.1)),
+            highlight: true,
+            cursor: None,
+            hovered: false,
+        }
+    }
+
+    /// A window with a button on it, and a popup over part of both
+    fn regions() -> HitRegions {
+        HitRegions {
+            regions: vec![
+                region((0.0, 0.0), (100.0, 100.0)),
+                region((10.0, 10.0), (30.0, 20.0)),
+                region((20.0, 15.0), (60.0, 50.0)),
+            ],
+            visuals: Default::default(),
+        }
+    }
+
+    #[test]
+    fn topmost() {
+        let regions = regions();
+        assert_eq!(regions.at(pos2(50.0, 80.0)), Some(0));
+        assert_eq!(regions.at(pos2(12.0, 12.0)), Some(1));
+        // The popup covers the button
+        assert_eq!(regions.at(pos2(25.0, 17.0)), Some(2));
+        assert_eq!(regions.at(pos2(40.0, 40.0)), Some(2));
+        // Edges count
+        assert_eq!(regions.at(pos2(10.0, 10.0)), Some(1));
+        assert_eq!(regions.at(pos2(100.0, 100.0)), Some(0));
+    }
+
+    #[test]
+    fn misses() {
+        let regions = regions();
+        assert_eq!(regions.at(pos2(-1.0, 50.0)), None);
+        assert_eq!(regions.at(pos2(50.0, 100.5)), None);
+        assert_eq!(regions.at(pos2(f32::NAN, 50.0)), None);
+        assert_eq!(HitRegions::default().at(pos2(0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn hovered() {
+        let mut regions = regions();
+        assert_eq!(regions.hovered(), None);
+        regions.regions[0].hovered = true;
+        regions.regions[1].hovered = true;
+        assert_eq!(regions.hovered(), Some(1));
+    }
+}
@@ -1,40 +1,96 @@
 pub use egui;
+pub mod codec;
+pub mod compact;
 pub mod delta_encoding;
+mod error;
 mod hash_abuse;
-use anyhow::Result;
+pub mod hit_test;
+pub mod recording;
+pub mod stats;
+pub mod transport;
+pub mod wire;
 
+pub use error::{Error, Result};
+
+use codec::Codec;
+use compact::ShapeEncoding;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use wire::{Wire, WireFormat};
+
+/// First message sent by the client once the socket opens. Its frame type
+/// (binary or text) picks the [`WireFormat`] unless a subprotocol was requested
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// Codecs this client is able to decompress
+    pub codecs: Vec<Codec>,
+    /// Shape encoding the client would like to receive
+    pub shape_encoding: ShapeEncoding,
+    /// Session of a previous connection to pick up again, if the server still has it
+    pub resume: Option<SessionToken>,
+    /// Whether to send [`ServerToClient::hit_regions`]
+    pub hit_regions: bool,
+}
+
+/// The server's answer to [`ClientHello`]; always the first message sent by the server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerHello {
+    /// Codec used in both directions for the rest of the connection
+    pub codec: Codec,
+    /// Shape encoding used for every [`ServerToClient`]
+    pub shape_encoding: ShapeEncoding,
+    /// For [`ClientHello::resume`] after losing this connection
+    pub session: SessionToken,
+    /// Whether the server picked up the session asked for, UI state and all
+    pub resumed: bool,
+}
+
+/// Names a client's session on the server. Random, so knowing it is as good
+/// as having been that client
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken(pub [u8; 16]);
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ServerToClient {
     pub update: delta_encoding::UpdateData,
+    /// Only if asked for, and only when they changed since the last update
+    pub hit_regions: Option<hit_test::HitRegions>,
+    /// Size the UI would like to be shown at, when it changed
+    pub size: Option<egui::Vec2>,
+    /// See [`stats::Meter`]; the client answers with its next message
+    pub ping: Option<u32>,
+    /// The client's last ping
+    pub pong: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientToServer {
     pub raw_input: egui::RawInput,
+    /// Physical pixels per remote point on the client, counting its own zoom
+    /// and any scaling. The server lays out for this instead of the
+    /// `native_pixels_per_point` in `raw_input`, so text and lines land on
+    /// the client's pixels
+    pub pixels_per_point: f32,
+    /// See [`stats::Meter`]; the server answers with an update right away
+    pub ping: Option<u32>,
+    /// The server's last ping
+    pub pong: Option<u32>,
 }
 
-#[cfg(not(feature = "json"))]
-pub fn serialize<T: Serialize>(val: &T) -> bincode::Result<Vec<u8>> {
-    let before = bincode::serialize(val)?;
-    let after = lz4_flex::compress_prepend_size(&before);
-    //dbg!(before.len(), after.len(), std::any::type_name_of_val(val));
-    Ok(after)
+/// bincode + lz4, i.e. what a connection uses before and without negotiation
+pub fn serialize<T: Serialize>(val: &T) -> Result<Vec<u8>> {
+    Wire::new(WireFormat::Bincode).encode(val)
 }
 
-#[cfg(not(feature = "json"))]
 pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
-    Ok(bincode::deserialize(&lz4_flex::decompress_size_prepended(&bytes)?)?)
-}
-
-#[cfg(feature = "json")]
-pub fn serialize<T: Serialize>(val: &T) -> serde_json::Result<Vec<u8>> {
-    serde_json::to_vec(val)
+    Wire::new(WireFormat::Bincode).decode(bytes)
 }
-
-#[cfg(feature = "json")]
-pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> serde_json::Result<T> {
-    serde_json::from_slice(bytes)
-}
-
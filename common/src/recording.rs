@@ -0,0 +1,346 @@
+//! Recording of sessions to a file, for reproducing what a user saw.
+//!
+//! File layout: one or more segments, each [`MAGIC`] then one entry per
+//! message: a little endian `u32` length followed by that many bytes of a
+//! compressed, bincode serialized [`RecordedEntry`]. Entries of a segment
+//! share a single streaming compression context, so they must be read in
+//! order. Each [`Recorder`] writes a segment of its own, so recording to the
+//! same file again appends to it.
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, Compressor, Decompressor},
+    ClientToServer, Error, Result, ServerToClient,
+};
+
+pub const MAGIC: &[u8; 8] = b"METERMR1";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum RecordedMessage {
+    ServerToClient(ServerToClient),
+    ClientToServer(ClientToServer),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    /// Since the recording started
+    pub time: Duration,
+    pub message: RecordedMessage,
+}
+
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+    record_input: bool,
+    compressor: Compressor,
+}
+
+impl Recorder {
+    /// Appends a new segment if the file exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Self::new(BufWriter::new(file))
+    }
+
+    pub fn new(mut writer: impl Write + Send + 'static) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+
+        let codec = if (Codec::ZstdStream { level: 3 }).is_supported() {
+            Codec::ZstdStream { level: 3 }
+        } else {
+            Codec::Lz4Stream
+        };
+
+        Ok(Self {
+            writer: Box::new(writer),
+            start: Instant::now(),
+            record_input: false,
+            compressor: Compressor::new(codec)?,
+        })
+    }
+
+    /// Also record what the user sent (off by default)
+    pub fn with_input(mut self, record_input: bool) -> Self {
+        self.record_input = record_input;
+        self
+    }
+
+    pub fn record_output(&mut self, msg: &ServerToClient) -> Result<()> {
+        self.write(RecordedMessage::ServerToClient(msg.clone()))
+    }
+
+    pub fn record_input(&mut self, msg: &ClientToServer) -> Result<()> {
+        if self.record_input {
+            self.write(RecordedMessage::ClientToServer(msg.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, message: RecordedMessage) -> Result<()> {
+        let entry = RecordedEntry {
+            time: self.start.elapsed(),
+            message,
+        };
+        let bytes = self.compressor.compress(&bincode::serialize(&entry)?)?;
+
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&bytes)?;
+        // Flush every entry, so a crash doesn't lose the interesting part
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads back entries written by a [`Recorder`]
+pub struct RecordingReader {
+    reader: Box<dyn Read + Send>,
+    decompressor: Decompressor,
+    /// Where the current segment's clock starts, so time keeps going forward
+    segment_start: Duration,
+    last_time: Duration,
+}
+
+impl RecordingReader {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn new(mut reader: impl Read + Send + 'static) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Protocol("Not a meterm recording".into()));
+        }
+
+        Ok(Self {
+            reader: Box::new(reader),
+            decompressor: Decompressor::new(),
+            segment_start: Duration::ZERO,
+            last_time: Duration::ZERO,
+        })
+    }
+
+    /// Reads the rest of the recording. A truncated final entry (e.g. from a crash) is ignored
+    pub fn read_all(self) -> Result<Vec<RecordedEntry>> {
+        let mut entries = vec![];
+        for entry in self {
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read_entry(&mut self) -> Result<Option<RecordedEntry>> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        // Far too long for an entry, so it's the next segment
+        if len == MAGIC[..4] {
+            let mut rest = [0; 4];
+            self.reader.read_exact(&mut rest)?;
+            if rest != MAGIC[4..] {
+                return Err(Error::Protocol("Corrupt segment header".into()));
+            }
+            self.decompressor = Decompressor::new();
+            self.segment_start = self.last_time;
+            return self.read_entry();
+        }
+
+        let mut bytes = vec![];
+        let len = u32::from_le_bytes(len) as u64;
+        (&mut self.reader).take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut entry: RecordedEntry =
+            bincode::deserialize(&self.decompressor.decompress(&bytes)?)?;
+        entry.time += self.segment_start;
+        self.last_time = entry.time;
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = Result<RecordedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::delta_encoding::UpdateData;
+
+    /// What the recorder wrote, readable while it still has the writer
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn output(ping: u32) -> ServerToClient {
+        ServerToClient {
+            update: UpdateData::FullUpdate(Default::default()),
+            hit_regions: None,
+            size: Some(egui::vec2(ping as f32, 2.0)),
+            ping: Some(ping),
+            pong: None,
+        }
+    }
+
+    fn input(pong: u32) -> ClientToServer {
+        ClientToServer {
+            raw_input: Default::default(),
+            pixels_per_point: 1.5,
+            ping: None,
+            pong: Some(pong),
+        }
+    }
+
+    /// Output 0, input 0, output 1, ... as recorded
+    fn record(entries: u32) -> Vec<u8> {
+        let file = Shared::default();
+        let mut recorder = Recorder::new(file.clone()).unwrap().with_input(true);
+        for i in 0..entries {
+            recorder.record_output(&output(i)).unwrap();
+            recorder.record_input(&input(i)).unwrap();
+        }
+        let bytes = file.0.lock().unwrap().clone();
+        bytes
+    }
+
+    fn read_all(bytes: Vec<u8>) -> Result<Vec<RecordedEntry>> {
+        RecordingReader::new(std::io::Cursor::new(bytes))?.read_all()
+    }
+
+    fn check(entries: &[RecordedEntry]) {
+        for (i, entry) in entries.iter().enumerate() {
+            let n = i as u32 / 2;
+            match (&entry.message, i % 2) {
+                (RecordedMessage::ServerToClient(msg), 0) => {
+                    assert_eq!(
+                        (msg.ping, msg.size),
+                        (Some(n), Some(egui::vec2(n as f32, 2.0)))
+                    );
+                }
+                (RecordedMessage::ClientToServer(msg), 1) => {
+                    assert_eq!((msg.pong, msg.pixels_per_point), (Some(n), 1.5));
+                }
+                _ => panic!("Entry {i} out of order"),
+            }
+        }
+        assert!(entries.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = read_all(record(5)).unwrap();
+        assert_eq!(entries.len(), 10);
+        check(&entries);
+    }
+
+    #[test]
+    fn input_off_by_default() {
+        let file = Shared::default();
+        let mut recorder = Recorder::new(file.clone()).unwrap();
+        recorder.record_output(&output(0)).unwrap();
+        recorder.record_input(&input(0)).unwrap();
+        let entries = read_all(file.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(entries.len(), 1);
+        check(&entries);
+    }
+
+    /// As if the recording process died mid-write
+    #[test]
+    fn truncated_tail() {
+        let whole = record(5);
+        let mut last_entry = MAGIC.len();
+        loop {
+            let len = u32::from_le_bytes(whole[last_entry..][..4].try_into().unwrap());
+            let next = last_entry + 4 + len as usize;
+            if next == whole.len() {
+                break;
+            }
+            last_entry = next;
+        }
+        // Cut into the body, down to just its length, and into the length
+        let last_len = whole.len() - last_entry;
+        for cut in [1, 3, last_len - 4, last_len - 2] {
+            let entries = read_all(whole[..whole.len() - cut].to_vec()).unwrap();
+            assert_eq!(entries.len(), 9, "cut {cut}");
+            check(&entries);
+        }
+
+        // Only part of a length after the last entry
+        let mut bytes = whole.clone();
+        bytes.extend([7, 0]);
+        assert_eq!(read_all(bytes).unwrap().len(), 10);
+    }
+
+    /// E.g. after a reconnect
+    #[test]
+    fn segments() {
+        let mut bytes = record(2);
+        bytes.extend(record(3));
+        let entries = read_all(bytes).unwrap();
+        assert_eq!(entries.len(), 10);
+        check(&entries[..4]);
+        check(&entries[4..]);
+        assert!(entries.windows(2).all(|w| w[0].time <= w[1].time));
+
+        // Cut into the second segment's header
+        let mut bytes = record(2);
+        bytes.extend(&MAGIC[..6]);
+        assert_eq!(read_all(bytes).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn create_appends() {
+        let path = std::env::temp_dir().join(format!("meterm-{}.rec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for ping in 0..2 {
+            let mut recorder = Recorder::create(&path).unwrap();
+            recorder.record_output(&output(ping)).unwrap();
+        }
+        let entries = RecordingReader::open(&path).unwrap().read_all().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        check(&entries[..1]);
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(
+            &entries[1].message,
+            RecordedMessage::ServerToClient(msg) if msg.ping == Some(1)
+        ));
+    }
+
+    #[test]
+    fn not_a_recording() {
+        assert!(read_all(b"METERMR0".to_vec()).is_err());
+        assert!(read_all(b"METER".to_vec()).is_err());
+    }
+}
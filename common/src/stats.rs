@@ -0,0 +1,296 @@
+//! How a connection performs, as seen from either end.
+//!
+//! Round trips are measured with ping ids sent along with regular messages
+//! (see `PROTOCOL.md`). Times are seconds on any clock the caller likes, since
+//! there's no `Instant` on the web.
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{
+    delta_encoding::{PartialUpdate, UpdateData},
+    wire::Wire,
+};
+
+/// Seconds between pings
+pub const PING_INTERVAL: f64 = 1.0;
+
+/// A ping unanswered for this long is given up on, and another one sent
+const PING_TIMEOUT: f64 = 10.0;
+
+/// Weight of each new round trip in the smoothed one, as TCP does
+const RTT_GAIN: f64 = 0.125;
+
+#[derive(Clone, Debug, Default)]
+pub struct TrafficStats {
+    /// Smoothed round trip time, once there was a pong
+    pub rtt: Option<Duration>,
+    /// Updates over the last second
+    pub fps: f32,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// As they went over the wire
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Before compression, or after decompression
+    pub raw_bytes_sent: u64,
+    pub raw_bytes_received: u64,
+    pub full_updates: u64,
+    pub partial_updates: u64,
+    /// Shapes of partial updates which were repeated from the last keyframe
+    pub shape_references: u64,
+    /// Shapes of partial updates which were sent anew
+    pub shapes: u64,
+}
+
+impl TrafficStats {
+    /// Raw over compressed bytes, both ways
+    pub fn compression_ratio(&self) -> f32 {
+        let wire = self.bytes_sent + self.bytes_received;
+        let raw = self.raw_bytes_sent + self.raw_bytes_received;
+        if wire == 0 {
+            return 1.0;
+        }
+        raw as f32 / wire as f32
+    }
+
+    /// Fraction of shapes in partial updates which didn't have to be sent
+    pub fn reference_hit_rate(&self) -> f32 {
+        let total = self.shape_references + self.shapes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.shape_references as f32 / total as f32
+    }
+}
+
+/// Keeps the [`TrafficStats`] of one end of a connection, and its pings
+#[derive(Default)]
+pub struct Meter {
+    stats: TrafficStats,
+    /// Times of the updates in the last second
+    updates: VecDeque<f64>,
+    next_ping: u32,
+    /// Id and time of the ping waiting for its pong
+    in_flight: Option<(u32, f64)>,
+    last_ping: Option<f64>,
+    /// The other end's ping, to answer with our next message
+    pong: Option<u32>,
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent(&mut self, bytes: usize) {
+        self.stats.messages_sent += 1;
+        self.stats.bytes_sent += bytes as u64;
+    }
+
+    pub fn received(&mut self, bytes: usize) {
+        self.stats.messages_received += 1;
+        self.stats.bytes_received += bytes as u64;
+    }
+
+    /// Count an update sent or received at `now`
+    pub fn update(&mut self, update: &UpdateData, now: f64) {
+        match update {
+            UpdateData::FullUpdate(_) => self.stats.full_updates += 1,
+            UpdateData::Partial(_, partials) => {
+                self.stats.partial_updates += 1;
+                for partial in partials {
+                    match partial {
+                        PartialUpdate::Reference(_) => self.stats.shape_references += 1,
+                        PartialUpdate::Shape(_) => self.stats.shapes += 1,
+                    }
+                }
+            }
+        }
+
+        self.updates.push_back(now);
+        self.forget_updates_before(now - 1.0);
+    }
+
+    /// Ping id for the next outgoing message, if it's time for one
+    pub fn ping(&mut self, now: f64) -> Option<u32> {
+        if self.in_flight.is_some_and(|(_, sent)| now - sent < PING_TIMEOUT) {
+            return None;
+        }
+        if self.last_ping.is_some_and(|last| now - last < PING_INTERVAL) {
+            return None;
+        }
+
+        let id = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.in_flight = Some((id, now));
+        self.last_ping = Some(now);
+        Some(id)
+    }
+
+    /// Answer to the other end's ping, for the next outgoing message
+    pub fn pong(&mut self) -> Option<u32> {
+        self.pong.take()
+    }
+
+    /// Whether a ping wasn't answered yet, so the next message shouldn't wait long
+    pub fn owes_pong(&self) -> bool {
+        self.pong.is_some()
+    }
+
+    /// Handle the ping fields of an incoming message
+    pub fn pinged(&mut self, now: f64, ping: Option<u32>, pong: Option<u32>) {
+        if ping.is_some() {
+            self.pong = ping;
+        }
+
+        let Some((id, sent)) = self.in_flight else {
+            return;
+        };
+        if pong != Some(id) {
+            return;
+        }
+        self.in_flight = None;
+
+        let sample = (now - sent).max(0.0);
+        let rtt = match self.stats.rtt {
+            Some(rtt) => rtt.as_secs_f64() * (1.0 - RTT_GAIN) + sample * RTT_GAIN,
+            None => sample,
+        };
+        self.stats.rtt = Some(Duration::from_secs_f64(rtt));
+    }
+
+    /// Stats as of `now`. Raw byte counts come from the `wire`, once there is one
+    pub fn stats(&self, now: f64, wire: Option<&Wire>) -> TrafficStats {
+        let recent = self.updates.iter().filter(|&&update| update >= now - 1.0);
+        let mut stats = TrafficStats {
+            fps: recent.count() as f32,
+            ..self.stats.clone()
+        };
+        if let Some(wire) = wire {
+            stats.raw_bytes_sent = wire.raw_bytes_encoded();
+            stats.raw_bytes_received = wire.raw_bytes_decoded();
+        }
+        stats
+    }
+
+    fn forget_updates_before(&mut self, time: f64) {
+        while self.updates.front().is_some_and(|&update| update < time) {
+            self.updates.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{epaint::ClippedShape, Rect, Shape};
+
+    use super::*;
+
+    fn assert_rtt(meter: &Meter, now: f64, secs: f64) {
+        let rtt = meter.stats(now, None).rtt.expect("rtt").as_secs_f64();
+        assert!((rtt - secs).abs() < 1e-6, "{rtt} != {secs}");
+    }
+
+    #[test]
+    fn rtt_smoothing() {
+        let mut meter = Meter::new();
+        assert!(meter.stats(0.0, None).rtt.is_none());
+
+        let id = meter.ping(0.0).unwrap();
+        // Someone else's pong
+        meter.pinged(0.1, None, Some(id + 1));
+        assert!(meter.stats(0.1, None).rtt.is_none());
+        // The first sample is taken as is
+        meter.pinged(0.2, None, Some(id));
+        assert_rtt(&meter, 0.2, 0.2);
+        // Only once
+        meter.pinged(0.9, None, Some(id));
+        assert_rtt(&meter, 0.9, 0.2);
+
+        let id = meter.ping(1.0).unwrap();
+        meter.pinged(1.6, None, Some(id));
+        assert_rtt(&meter, 1.6, 0.2 * (1.0 - RTT_GAIN) + 0.6 * RTT_GAIN);
+    }
+
+    #[test]
+    fn ping_interval_and_timeout() {
+        let mut meter = Meter::new();
+        let first = meter.ping(0.0).unwrap();
+        assert_eq!(meter.ping(0.5), None);
+        // Still waiting for the pong
+        assert_eq!(meter.ping(PING_TIMEOUT - 0.1), None);
+
+        let second = meter.ping(PING_TIMEOUT).unwrap();
+        assert_ne!(first, second);
+        // Too late to count
+        meter.pinged(PING_TIMEOUT + 0.1, None, Some(first));
+        assert!(meter.stats(PING_TIMEOUT + 0.1, None).rtt.is_none());
+
+        meter.pinged(PING_TIMEOUT + 0.3, None, Some(second));
+        assert_rtt(&meter, PING_TIMEOUT + 0.3, 0.3);
+        assert_eq!(meter.ping(PING_TIMEOUT + 0.5), None);
+        assert!(meter.ping(PING_TIMEOUT + PING_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn pong() {
+        let mut meter = Meter::new();
+        assert!(!meter.owes_pong());
+        meter.pinged(0.0, Some(7), None);
+        assert!(meter.owes_pong());
+        assert_eq!(meter.pong(), Some(7));
+        assert_eq!(meter.pong(), None);
+        assert!(!meter.owes_pong());
+    }
+
+    #[test]
+    fn updates() {
+        let shape = || {
+            PartialUpdate::Shape(ClippedShape {
+                clip_rect: Rect::EVERYTHING,
+                shape: Shape::Noop,
+            })
+        };
+        let partial = UpdateData::Partial(
+            Default::default(),
+            vec![
+                PartialUpdate::Reference(0),
+                shape(),
+                PartialUpdate::Reference(1),
+                PartialUpdate::Reference(2),
+            ],
+        );
+
+        let mut meter = Meter::new();
+        assert_eq!(meter.stats(0.0, None).reference_hit_rate(), 0.0);
+        meter.update(&UpdateData::FullUpdate(Default::default()), 0.0);
+        meter.update(&partial, 0.5);
+        meter.update(&partial, 0.9);
+        meter.update(&partial, 1.2);
+
+        let stats = meter.stats(1.2, None);
+        assert_eq!((stats.full_updates, stats.partial_updates), (1, 3));
+        assert_eq!((stats.shape_references, stats.shapes), (9, 3));
+        assert_eq!(stats.reference_hit_rate(), 0.75);
+        // Over the last second
+        assert_eq!(stats.fps, 3.0);
+        assert_eq!(meter.stats(5.0, None).fps, 0.0);
+    }
+
+    #[test]
+    fn compression_ratio() {
+        assert_eq!(TrafficStats::default().compression_ratio(), 1.0);
+
+        let mut meter = Meter::new();
+        meter.sent(100);
+        meter.sent(20);
+        meter.received(30);
+        let stats = TrafficStats {
+            raw_bytes_sent: 400,
+            raw_bytes_received: 200,
+            ..meter.stats(0.0, None)
+        };
+        assert_eq!((stats.messages_sent, stats.messages_received), (2, 1));
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (120, 30));
+        assert_eq!(stats.compression_ratio(), 4.0);
+    }
+}
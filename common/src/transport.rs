@@ -0,0 +1,177 @@
+//! What connections are made of. The protocol only needs ordered, framed
+//! messages, so anything which can carry those (a WebSocket, an in-process
+//! channel, a byte stream) can connect a viewer to a server.
+use std::sync::{
+    mpsc::{channel, Receiver, Sender, TryRecvError},
+    Arc, Mutex,
+};
+
+use crate::wire::WireFormat;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod stream;
+
+/// One message. Which kind it is matters, since the client's hello picks the
+/// [`WireFormat`] by it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+impl Frame {
+    /// Wrap encoded bytes in the kind of frame `format` is sent as
+    pub fn new(format: WireFormat, bytes: Vec<u8>) -> Self {
+        match format {
+            WireFormat::Bincode => Self::Binary(bytes),
+            WireFormat::Json => Self::Text(String::from_utf8(bytes).expect("JSON is UTF-8")),
+        }
+    }
+
+    pub fn format(&self) -> WireFormat {
+        match self {
+            Self::Binary(_) => WireFormat::Bincode,
+            Self::Text(_) => WireFormat::Json,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Binary(bytes) => bytes,
+            Self::Text(text) => text.as_bytes(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TransportEvent {
+    /// Ready to send; the client sends its hello on this
+    Opened,
+    Frame(Frame),
+    Error(String),
+    /// The other end hung up. Nothing follows this
+    Closed,
+}
+
+/// One end of a connection. Neither method may block for long, since both
+/// are called from the UI thread
+pub trait Transport: Send {
+    fn send(&mut self, frame: Frame);
+
+    fn try_recv(&mut self) -> Option<TransportEvent>;
+
+    /// Whether [`Transport::send`] has nowhere to put a frame right now, e.g.
+    /// a full queue to a slow peer. The server skips frames while it is
+    fn is_backed_up(&self) -> bool {
+        false
+    }
+
+    /// Format the connection was set up for, if any (e.g. a WebSocket
+    /// subprotocol). Overrides the frame kind of the client's hello
+    fn wire_format(&self) -> Option<WireFormat> {
+        None
+    }
+}
+
+type Wakeup = Arc<Mutex<Option<Box<dyn Fn() + Send + Sync>>>>;
+
+/// In-memory connection, for running a server and its viewer in the same process
+pub struct Loopback {
+    tx: Sender<TransportEvent>,
+    rx: Receiver<TransportEvent>,
+    /// Called when something is sent to this end
+    wakeup: Wakeup,
+    peer_wakeup: Wakeup,
+    closed: bool,
+}
+
+impl Loopback {
+    /// Both ends start out with an [`TransportEvent::Opened`] queued
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let a_wakeup = Wakeup::default();
+        let b_wakeup = Wakeup::default();
+
+        a_tx.send(TransportEvent::Opened).unwrap();
+        b_tx.send(TransportEvent::Opened).unwrap();
+
+        let a = Self {
+            tx: a_tx,
+            rx: a_rx,
+            wakeup: a_wakeup.clone(),
+            peer_wakeup: b_wakeup.clone(),
+            closed: false,
+        };
+        let b = Self {
+            tx: b_tx,
+            rx: b_rx,
+            wakeup: b_wakeup,
+            peer_wakeup: a_wakeup,
+            closed: false,
+        };
+        (a, b)
+    }
+
+    /// E.g. `ctx.request_repaint()`, so the viewer doesn't have to poll
+    pub fn set_wakeup(&self, wakeup: impl Fn() + Send + Sync + 'static) {
+        *self.wakeup.lock().unwrap() = Some(Box::new(wakeup));
+    }
+
+    fn wake_peer(&self) {
+        if let Some(wakeup) = &*self.peer_wakeup.lock().unwrap() {
+            wakeup();
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, frame: Frame) {
+        if self.tx.send(TransportEvent::Frame(frame)).is_ok() {
+            self.wake_peer();
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        if self.closed {
+            return None;
+        }
+
+        match self.rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                Some(TransportEvent::Closed)
+            }
+        }
+    }
+}
+
+impl Drop for Loopback {
+    /// So the other end notices we're gone
+    fn drop(&mut self) {
+        self.wake_peer();
+    }
+}
+
+/// Hands out [`Loopback`]s, keeping the other end for whoever holds the
+/// receiver (usually a `meterm_server::Server`)
+#[derive(Clone, Debug)]
+pub struct LoopbackConnector {
+    tx: Sender<Loopback>,
+}
+
+impl LoopbackConnector {
+    pub fn new() -> (Self, Receiver<Loopback>) {
+        let (tx, rx) = channel();
+        (Self { tx }, rx)
+    }
+
+    /// Returns the client's end. If the receiver is gone, it closes right away
+    pub fn connect(&self) -> Loopback {
+        let (client, server) = Loopback::pair();
+        let _ = self.tx.send(server);
+        client
+    }
+}
@@ -0,0 +1,360 @@
+//! Frames over a plain byte stream, for local IPC without a web stack.
+//!
+//! Each frame is a kind byte (0 binary, 1 text), the payload length as a
+//! little endian `u32`, then the payload.
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    process::Child,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError},
+        Arc,
+    },
+};
+
+use super::{Frame, Transport, TransportEvent, Wakeup};
+
+/// Longer frames are taken as garbage on the stream rather than a message,
+/// unless changed with [`StreamTransport::set_max_frame_len`]
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 << 20;
+
+/// Frames waiting for the writer thread, beyond which [`Transport::is_backed_up`]
+const QUEUE_LEN: usize = 100;
+
+/// Reading and writing each happen on their own thread, so neither
+/// [`Transport`] method blocks
+pub struct StreamTransport {
+    rx: Receiver<TransportEvent>,
+    tx: SyncSender<Frame>,
+    /// Frames sent but not written yet
+    queued: Arc<AtomicUsize>,
+    max_frame_len: Arc<AtomicUsize>,
+    wakeup: Wakeup,
+    /// Unblocks the reader thread when we hang up
+    shutdown: Option<Box<dyn Fn() + Send>>,
+    closed: bool,
+}
+
+impl StreamTransport {
+    /// `reader` and `writer` are usually the two halves of one stream
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        let (event_tx, event_rx) = channel();
+        let (frame_tx, frame_rx) = sync_channel(QUEUE_LEN);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let max_frame_len = Arc::new(AtomicUsize::new(DEFAULT_MAX_FRAME_LEN));
+        let wakeup = Wakeup::default();
+
+        // The stream is connected already
+        event_tx.send(TransportEvent::Opened).unwrap();
+
+        let reader_wakeup = wakeup.clone();
+        let reader_max = max_frame_len.clone();
+        std::thread::spawn(move || read_loop(reader, event_tx, reader_wakeup, reader_max));
+        let writer_queued = queued.clone();
+        let writer_max = max_frame_len.clone();
+        std::thread::spawn(move || write_loop(writer, frame_rx, writer_queued, writer_max));
+
+        Self {
+            rx: event_rx,
+            tx: frame_tx,
+            queued,
+            max_frame_len,
+            wakeup,
+            shutdown: None,
+            closed: false,
+        }
+    }
+
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let shutdown = stream.try_clone()?;
+        let mut transport = Self::new(reader, stream);
+        transport.shutdown = Some(Box::new(move || {
+            let _ = shutdown.shutdown(Shutdown::Both);
+        }));
+        Ok(transport)
+    }
+
+    #[cfg(unix)]
+    pub fn unix(stream: std::os::unix::net::UnixStream) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        let shutdown = stream.try_clone()?;
+        let mut transport = Self::new(reader, stream);
+        transport.shutdown = Some(Box::new(move || {
+            let _ = shutdown.shutdown(Shutdown::Both);
+        }));
+        Ok(transport)
+    }
+
+    /// Our own stdin and stdout, e.g. in a helper process spawned by a CLI
+    /// tool. Nothing else may print to stdout then; logs have to go to stderr
+    pub fn stdio() -> Self {
+        Self::new(io::stdin(), io::stdout())
+    }
+
+    /// Talk to a child process spawned with piped stdin and stdout. `None` if
+    /// either wasn't piped (or was taken already)
+    pub fn child(child: &mut Child) -> Option<Self> {
+        let stdout = child.stdout.take()?;
+        let stdin = child.stdin.take()?;
+        Some(Self::new(stdout, stdin))
+    }
+
+    /// E.g. `ctx.request_repaint()`, so the viewer doesn't have to poll
+    pub fn set_wakeup(&self, wakeup: impl Fn() + Send + Sync + 'static) {
+        *self.wakeup.lock().unwrap() = Some(Box::new(wakeup));
+    }
+
+    /// Longest frame either way. Both ends should agree, since a longer one
+    /// ends the connection. [`DEFAULT_MAX_FRAME_LEN`] unless set
+    pub fn set_max_frame_len(&self, len: usize) {
+        self.max_frame_len.store(len, Ordering::Relaxed);
+    }
+}
+
+impl Transport for StreamTransport {
+    fn send(&mut self, frame: Frame) {
+        // Never wait on the writer. Servers check `is_backed_up` first, so
+        // only a closed stream loses anything here
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        if self.tx.try_send(frame).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        if self.closed {
+            return None;
+        }
+
+        match self.rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                Some(TransportEvent::Closed)
+            }
+        }
+    }
+
+    fn is_backed_up(&self) -> bool {
+        self.queued.load(Ordering::Relaxed) >= QUEUE_LEN
+    }
+}
+
+impl Drop for StreamTransport {
+    fn drop(&mut self) {
+        if let Some(shutdown) = &self.shutdown {
+            shutdown();
+        }
+    }
+}
+
+fn read_loop(
+    mut reader: impl Read,
+    tx: Sender<TransportEvent>,
+    wakeup: Wakeup,
+    max_len: Arc<AtomicUsize>,
+) {
+    loop {
+        let event = match read_frame(&mut reader, max_len.load(Ordering::Relaxed)) {
+            Ok(frame) => TransportEvent::Frame(frame),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => TransportEvent::Closed,
+            Err(e) => TransportEvent::Error(e.to_string()),
+        };
+        let done = !matches!(event, TransportEvent::Frame(_));
+
+        // Nobody's listening anymore
+        if tx.send(event).is_err() {
+            return;
+        }
+        if let Some(wakeup) = &*wakeup.lock().unwrap() {
+            wakeup();
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+fn write_loop(
+    mut writer: impl Write,
+    rx: Receiver<Frame>,
+    queued: Arc<AtomicUsize>,
+    max_len: Arc<AtomicUsize>,
+) {
+    for frame in rx {
+        let result = write_frame(&mut writer, &frame, max_len.load(Ordering::Relaxed));
+        queued.fetch_sub(1, Ordering::Relaxed);
+        if result.is_err() {
+            // The reader notices too, and reports it. After a frame too long
+            // to send, the peer can't make sense of what follows either
+            return;
+        }
+    }
+}
+
+/// Refuses frames longer than `max_len`, which the peer wouldn't read
+pub fn write_frame(writer: &mut impl Write, frame: &Frame, max_len: usize) -> io::Result<()> {
+    let kind = match frame {
+        Frame::Binary(_) => 0u8,
+        Frame::Text(_) => 1u8,
+    };
+    let bytes = frame.bytes();
+    if bytes.len() > max_len.min(u32::MAX as usize) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Frame too long",
+        ));
+    }
+    writer.write_all(&[kind])?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    writer.flush()
+}
+
+pub fn read_frame(reader: &mut impl Read, max_len: usize) -> io::Result<Frame> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too long"));
+    }
+
+    // Grows as the bytes actually arrive, rather than trusting the length
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    match header[0] {
+        0 => Ok(Frame::Binary(bytes)),
+        1 => String::from_utf8(bytes)
+            .map(Frame::Text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        kind => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown frame kind {kind}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(kind: u8, len: u32) -> Vec<u8> {
+        let mut bytes = vec![kind];
+        bytes.extend(len.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = [
+            Frame::Binary(vec![1, 2, 3]),
+            Frame::Text("Hello".into()),
+            Frame::Binary(vec![]),
+            Frame::Text(String::new()),
+        ];
+        let mut stream = vec![];
+        for frame in &frames {
+            write_frame(&mut stream, frame, DEFAULT_MAX_FRAME_LEN).unwrap();
+        }
+        assert_eq!(&stream[..8], &[0, 3, 0, 0, 0, 1, 2, 3]);
+
+        let mut reader = stream.as_slice();
+        for frame in &frames {
+            assert_eq!(
+                &read_frame(&mut reader, DEFAULT_MAX_FRAME_LEN).unwrap(),
+                frame
+            );
+        }
+        let end = read_frame(&mut reader, DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(end.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn refuses_to_write_too_long() {
+        let frame = Frame::Binary(vec![0; 9]);
+        let mut stream = vec![];
+        let err = write_frame(&mut stream, &frame, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn refuses_to_read_too_long() {
+        let mut stream = header(0, 9);
+        stream.extend([0; 9]);
+        let err = read_frame(&mut stream.as_slice(), 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn eof_in_header() {
+        let stream = &header(0, 3)[..3];
+        let err = read_frame(&mut &stream[..], DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn eof_in_body() {
+        let mut stream = header(1, 10);
+        stream.extend(b"abc");
+        let err = read_frame(&mut stream.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Claiming the most there may be doesn't get it allocated
+        let stream = header(0, DEFAULT_MAX_FRAME_LEN as u32);
+        let err = read_frame(&mut stream.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let mut stream = header(7, 1);
+        stream.push(0);
+        let err = read_frame(&mut stream.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Writes nothing until the gate opens
+    struct Gated(Arc<std::sync::Mutex<()>>);
+
+    impl Write for Gated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _open = self.0.lock().unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backed_up() {
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let mut transport = StreamTransport::new(io::empty(), Gated(gate.clone()));
+
+        for _ in 0..QUEUE_LEN {
+            assert!(!transport.is_backed_up());
+            transport.send(Frame::Binary(vec![1]));
+        }
+        assert!(transport.is_backed_up());
+        // Dropped rather than waited on
+        transport.send(Frame::Binary(vec![1]));
+
+        drop(closed);
+        let start = std::time::Instant::now();
+        while transport.is_backed_up() {
+            assert!(start.elapsed().as_secs() < 10, "Writer never caught up");
+            std::thread::yield_now();
+        }
+    }
+}
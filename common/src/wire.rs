@@ -0,0 +1,261 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    codec::{Codec, Compressor, Decompressor},
+    compact::{self, ShapeEncoding},
+    Result, ServerHello, ServerToClient,
+};
+
+/// How messages are serialized. Chosen per connection by the client, see `PROTOCOL.md`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    /// bincode, compressed with the negotiated [`Codec`]. Sent as binary frames
+    #[default]
+    Bincode,
+    /// Plain, uncompressed JSON. Sent as text frames; meant for debugging and non-Rust clients
+    Json,
+}
+
+impl WireFormat {
+    /// WebSocket subprotocol name
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Self::Bincode => "meterm.bincode",
+            Self::Json => "meterm.json",
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        [Self::Bincode, Self::Json]
+            .into_iter()
+            .find(|format| format.subprotocol() == name.trim())
+    }
+}
+
+/// Serialization state for one end of a single connection
+pub struct Wire {
+    format: WireFormat,
+    shape_encoding: ShapeEncoding,
+    compressor: Compressor,
+    decompressor: Decompressor,
+    raw_bytes_encoded: u64,
+    raw_bytes_decoded: u64,
+}
+
+impl Wire {
+    /// Until the handshake completes, messages are sent with the default codec
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            shape_encoding: ShapeEncoding::Standard,
+            compressor: Compressor::default(),
+            decompressor: Decompressor::new(),
+            raw_bytes_encoded: 0,
+            raw_bytes_decoded: 0,
+        }
+    }
+
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.compressor.codec()
+    }
+
+    pub fn shape_encoding(&self) -> ShapeEncoding {
+        self.shape_encoding
+    }
+
+    /// Size of everything encoded so far, before compression
+    pub fn raw_bytes_encoded(&self) -> u64 {
+        self.raw_bytes_encoded
+    }
+
+    /// Size of everything decoded so far, after decompression
+    pub fn raw_bytes_decoded(&self) -> u64 {
+        self.raw_bytes_decoded
+    }
+
+    /// Switch to whatever the server decided on. Must happen right after the
+    /// hello is sent (server) or received (client)
+    pub fn apply_hello(&mut self, hello: &ServerHello) -> Result<()> {
+        if self.format == WireFormat::Json {
+            return Ok(());
+        }
+
+        self.compressor = Compressor::new(hello.codec)?;
+        self.shape_encoding = hello.shape_encoding;
+        Ok(())
+    }
+
+    pub fn encode<T: Serialize>(&mut self, val: &T) -> Result<Vec<u8>> {
+        match self.format {
+            WireFormat::Bincode => self.compress(&bincode::serialize(val)?),
+            WireFormat::Json => {
+                let mut value = serde_json::to_value(val)?;
+                for_each_id(&mut value, &mut |id| {
+                    if let Some(n) = id.as_u64() {
+                        *id = Value::String(n.to_string());
+                    }
+                });
+                let bytes = serde_json::to_vec(&value)?;
+                self.raw_bytes_encoded += bytes.len() as u64;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&mut self, bytes: &[u8]) -> Result<T> {
+        match self.format {
+            WireFormat::Bincode => Ok(bincode::deserialize(&self.decompress(bytes)?)?),
+            WireFormat::Json => {
+                self.raw_bytes_decoded += bytes.len() as u64;
+                let mut value: Value = serde_json::from_slice(bytes)?;
+                for_each_id(&mut value, &mut |id| {
+                    if let Some(n) = id.as_str().and_then(|id| id.parse::<u64>().ok()) {
+                        *id = n.into();
+                    }
+                });
+                Ok(serde_json::from_value(value)?)
+            }
+        }
+    }
+
+    pub fn encode_update(&mut self, val: &ServerToClient) -> Result<Vec<u8>> {
+        match (self.format, self.shape_encoding) {
+            (WireFormat::Bincode, ShapeEncoding::Compact) => {
+                self.compress(&compact::serialize(val)?)
+            }
+            _ => self.encode(val),
+        }
+    }
+
+    pub fn decode_update(&mut self, bytes: &[u8]) -> Result<ServerToClient> {
+        match (self.format, self.shape_encoding) {
+            (WireFormat::Bincode, ShapeEncoding::Compact) => {
+                compact::deserialize(&self.decompress(bytes)?)
+            }
+            _ => self.decode(bytes),
+        }
+    }
+
+    fn compress(&mut self, raw: &[u8]) -> Result<Vec<u8>> {
+        self.raw_bytes_encoded += raw.len() as u64;
+        self.compressor.compress(raw)
+    }
+
+    fn decompress(&mut self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let raw = self.decompressor.decompress(bytes)?;
+        self.raw_bytes_decoded += raw.len() as u64;
+        Ok(raw)
+    }
+}
+
+/// Fields holding a `ViewportId`, which is a hash and so usually beyond the
+/// 2^53 a JavaScript number holds exactly. They're strings in JSON
+const ID_FIELDS: [&str; 2] = ["viewport_id", "parent"];
+
+fn for_each_id(value: &mut Value, f: &mut impl FnMut(&mut Value)) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields {
+                if ID_FIELDS.contains(&name.as_str()) {
+                    f(value);
+                } else {
+                    for_each_id(value, f);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| for_each_id(value, f)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{RawInput, ViewportId, ViewportInfo};
+
+    use super::*;
+    use crate::{delta_encoding::UpdateData, ClientToServer};
+
+    fn input() -> ClientToServer {
+        let mut raw_input = RawInput::default();
+        raw_input
+            .events
+            .push(egui::Event::Text("18446744073709551615".into()));
+        let child = ViewportId::from_hash_of("child");
+        raw_input.viewports.insert(
+            child,
+            ViewportInfo {
+                parent: Some(ViewportId::ROOT),
+                ..Default::default()
+            },
+        );
+        ClientToServer {
+            raw_input,
+            pixels_per_point: 1.0,
+            ping: None,
+            pong: None,
+        }
+    }
+
+    #[test]
+    fn json_ids_are_strings() {
+        let mut wire = Wire::new(WireFormat::Json);
+        let bytes = wire.encode(&input()).unwrap();
+        let json = String::from_utf8(bytes.clone()).unwrap();
+        assert!(
+            json.contains(r#""viewport_id":"18446744073709551615""#),
+            "{json}"
+        );
+        assert!(
+            json.contains(r#""parent":"18446744073709551615""#),
+            "{json}"
+        );
+
+        let decoded: ClientToServer = wire.decode(&bytes).unwrap();
+        assert_eq!(decoded.raw_input, input().raw_input);
+    }
+
+    #[test]
+    fn json_update() {
+        let ctx = egui::Context::default();
+        let output = ctx.run(Default::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| ui.label("Hi"));
+        });
+        let update = ServerToClient {
+            update: UpdateData::FullUpdate(output),
+            hit_regions: None,
+            size: None,
+            ping: None,
+            pong: None,
+        };
+
+        let mut wire = Wire::new(WireFormat::Json);
+        let bytes = wire.encode_update(&update).unwrap();
+        let json = String::from_utf8(bytes.clone()).unwrap();
+        assert!(
+            json.contains(r#""parent":"18446744073709551615""#),
+            "{json}"
+        );
+        let decoded = wire.decode_update(&bytes).unwrap();
+        let UpdateData::FullUpdate(output) = decoded.update else {
+            panic!("Full update came back partial");
+        };
+        assert_eq!(
+            output.viewport_output[&ViewportId::ROOT].parent,
+            ViewportId::ROOT
+        );
+    }
+
+    /// As an older client would send them
+    #[test]
+    fn json_ids_as_numbers() {
+        let mut wire = Wire::new(WireFormat::Json);
+        let bytes = serde_json::to_vec(&input()).unwrap();
+        let decoded: ClientToServer = wire.decode(&bytes).unwrap();
+        assert_eq!(decoded.raw_input, input().raw_input);
+    }
+}
@@ -0,0 +1,52 @@
+//! With the `web-viewer` feature, embeds the web viewer bundle found at
+//! `$METERM_VIEWER_DIST` (a `trunk build` output directory, e.g. viewer/dist).
+use std::{
+    env,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=METERM_VIEWER_DIST");
+    if env::var_os("CARGO_FEATURE_WEB_VIEWER").is_none() {
+        return;
+    }
+
+    let dist = PathBuf::from(env::var("METERM_VIEWER_DIST").expect(
+        "The web-viewer feature needs METERM_VIEWER_DIST set to a built viewer, e.g. viewer/dist after `trunk build --release` in viewer/",
+    ));
+    println!("cargo:rerun-if-changed={}", dist.display());
+
+    let mut files = vec![];
+    collect(&dist, "", &mut files);
+    assert!(
+        files.iter().any(|(name, _)| name == "index.html"),
+        "No index.html in {}",
+        dist.display()
+    );
+
+    let mut out = String::from("pub static FILES: &[(&str, &[u8])] = &[\n");
+    for (name, path) in files {
+        let path = path.canonicalize().unwrap();
+        writeln!(out, "    ({name:?}, include_bytes!({path:?})),").unwrap();
+    }
+    out += "];\n";
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("web_viewer_files.rs");
+    std::fs::write(out_path, out).unwrap();
+}
+
+fn collect(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) {
+    let entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Reading web viewer at {}; {e}", dir.display()));
+    for entry in entries {
+        let entry = entry.unwrap();
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if entry.file_type().unwrap().is_dir() {
+            collect(&entry.path(), &format!("{name}/"), files);
+        } else {
+            println!("cargo:rerun-if-changed={}", entry.path().display());
+            files.push((name, entry.path()));
+        }
+    }
+}
@@ -2,15 +2,25 @@ use meterm_server::{Server, egui};
 
 fn main() {
     let mut server = Server::new("0.0.0.0:5000");
-    let mut counter = 0;
+    let mut app = App::default();
 
     loop {
-        server.for_each_client(|ctx| {
-            egui::CentralPanel::default().show(ctx, |ui| {
-                if ui.button(format!("Click to increase! ({})", counter)).clicked() {
-                    counter += 1;
-                }
-            });
+        server.for_each_client(|ctx| app.ui(ctx));
+    }
+}
+
+/// Also run by the codec bench
+#[derive(Default)]
+pub struct App {
+    counter: usize,
+}
+
+impl App {
+    pub fn ui(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if ui.button(format!("Click to increase! ({})", self.counter)).clicked() {
+                self.counter += 1;
+            }
         });
     }
 }
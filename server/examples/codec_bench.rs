@@ -0,0 +1,232 @@
+//! Compares compression ratio and latency of each codec on frames produced by the shipped examples,
+//! for both shape encodings. Also checks that compact shapes round trip within tolerance.
+//!
+//! Usage:
+//!     cargo run --release --example codec_bench --features zstd
+//!     cargo run --release --example codec_bench --features zstd -- --train common/src/egui_shapes.zdict
+use std::time::{Duration, Instant};
+
+use meterm_common::{
+    codec::{Codec, Compressor, Decompressor},
+    compact::{self, ShapeEncoding, MAX_POSITION_ERROR},
+    delta_encoding::Decoder,
+    egui::FullOutput,
+    ServerToClient,
+};
+use meterm_server::{
+    egui::{Context, Event, PointerButton, Rect, Vec2},
+    testing::Harness,
+};
+
+// The UIs of the shipped examples
+#[allow(dead_code)]
+#[path = "basic.rs"]
+mod basic;
+#[allow(dead_code)]
+#[path = "demo_lib.rs"]
+mod demo_lib;
+#[allow(dead_code)]
+#[path = "paint.rs"]
+mod paint;
+#[allow(dead_code)]
+#[path = "using_memory.rs"]
+mod using_memory;
+
+const FRAMES: usize = 300;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let mut basic = basic::App::default();
+    let mut using_memory = using_memory::App::default();
+    let mut paint = paint::Painting::default();
+    let scenes: Vec<(&str, Box<dyn FnMut(&Context)>)> = vec![
+        ("basic", Box::new(move |ctx| basic.ui(ctx))),
+        ("using_memory", Box::new(move |ctx| using_memory.ui(ctx))),
+        ("paint", Box::new(move |ctx| paint.ui(ctx))),
+        ("demo_lib", Box::new(demo_lib::ui)),
+    ];
+
+    let packets: Vec<(&str, Vec<ServerToClient>)> = scenes
+        .into_iter()
+        .map(|(name, ui_func)| (name, record_packets(ui_func)))
+        .collect();
+
+    for (name, packets) in &packets {
+        check_compact_round_trip(name, packets);
+    }
+
+    let serialized = |shape_encoding| -> Vec<(&str, Vec<Vec<u8>>)> {
+        packets
+            .iter()
+            .map(|(name, packets)| {
+                let frames = packets
+                    .iter()
+                    .map(|packet| match shape_encoding {
+                        ShapeEncoding::Standard => bincode::serialize(packet).unwrap(),
+                        ShapeEncoding::Compact => compact::serialize(packet).unwrap(),
+                    })
+                    .collect();
+                (*name, frames)
+            })
+            .collect()
+    };
+
+    if let Some(pos) = args.iter().position(|arg| arg == "--train") {
+        let path = args.get(pos + 1).expect("--train <output path>");
+        train(path, &serialized(ShapeEncoding::Standard));
+        return;
+    }
+
+    println!(
+        "{:<14} {:<9} {:<28} {:>8} {:>12} {:>14} {:>14}",
+        "scene", "shapes", "codec", "ratio", "bytes/frame", "compress us", "decompress us"
+    );
+
+    let standard = serialized(ShapeEncoding::Standard);
+    let compact = serialized(ShapeEncoding::Compact);
+    for ((name, frames), (_, compact_frames)) in standard.iter().zip(&compact) {
+        let raw_len: usize = frames.iter().map(|frame| frame.len()).sum();
+        for (shape_encoding, frames) in [("standard", frames), ("compact", compact_frames)] {
+            bench_codecs(name, shape_encoding, frames, raw_len);
+        }
+    }
+}
+
+/// Ratios are relative to the uncompressed standard encoding (`raw_len`)
+fn bench_codecs(name: &str, shape_encoding: &str, frames: &[Vec<u8>], raw_len: usize) {
+    for codec in codecs() {
+        let mut compressor = Compressor::new(codec).unwrap();
+        let mut decompressor = Decompressor::new();
+
+        let mut compressed_len = 0;
+        let mut compress_time = Duration::ZERO;
+        let mut decompress_time = Duration::ZERO;
+
+        for frame in frames {
+            let start = Instant::now();
+            let compressed = compressor.compress(frame).unwrap();
+            compress_time += start.elapsed();
+
+            let start = Instant::now();
+            let decompressed = decompressor.decompress(&compressed).unwrap();
+            decompress_time += start.elapsed();
+
+            assert_eq!(&decompressed, frame);
+            compressed_len += compressed.len();
+        }
+
+        let n = frames.len() as f64;
+        println!(
+            "{:<14} {:<9} {:<28} {:>7.1}% {:>12} {:>14.1} {:>14.1}",
+            name,
+            shape_encoding,
+            format!("{codec:?}"),
+            100. * compressed_len as f64 / raw_len as f64,
+            compressed_len / frames.len(),
+            compress_time.as_secs_f64() * 1e6 / n,
+            decompress_time.as_secs_f64() * 1e6 / n,
+        );
+    }
+}
+
+fn codecs() -> Vec<Codec> {
+    let mut codecs = vec![Codec::None, Codec::Lz4, Codec::Lz4Stream];
+    if (Codec::Zstd { level: 1 }).is_supported() {
+        for level in [1, 3, 9, 19] {
+            codecs.push(Codec::Zstd { level });
+        }
+        for level in [1, 3, 9] {
+            codecs.push(Codec::ZstdDict { level });
+        }
+        for level in [1, 3, 9] {
+            codecs.push(Codec::ZstdStream { level });
+        }
+    }
+    codecs
+}
+
+#[cfg(feature = "zstd")]
+fn train(path: &str, recorded: &[(&str, Vec<Vec<u8>>)]) {
+    let samples: Vec<Vec<u8>> = recorded
+        .iter()
+        .flat_map(|(_, frames)| frames.iter().cloned())
+        .collect();
+    let dict = meterm_common::codec::train_dictionary(&samples, 32 * 1024).unwrap();
+    std::fs::write(path, &dict).unwrap();
+    println!("Wrote {} byte dictionary to {path}", dict.len());
+}
+
+#[cfg(not(feature = "zstd"))]
+fn train(_: &str, _: &[(&str, Vec<Vec<u8>>)]) {
+    panic!("Training requires the zstd feature");
+}
+
+/// Decodes both encodings and asserts every shape lands within quantization error of the original
+fn check_compact_round_trip(name: &str, packets: &[ServerToClient]) {
+    let mut standard = Decoder::new();
+    let mut compact = Decoder::new();
+
+    for packet in packets {
+        let round_tripped = compact::deserialize(&compact::serialize(packet).unwrap()).unwrap();
+        let expected = standard.decode(packet.update.clone());
+        let actual = compact.decode(round_tripped.update);
+
+        let (Some(expected), Some(actual)) = (expected, actual) else {
+            continue;
+        };
+        assert_visually_equal(name, &expected, &actual);
+    }
+}
+
+fn assert_visually_equal(name: &str, expected: &FullOutput, actual: &FullOutput) {
+    assert_eq!(expected.shapes.len(), actual.shapes.len(), "{name}: shape count");
+
+    // Rounding of both corners, plus a little slack for float error
+    let tolerance = 2. * MAX_POSITION_ERROR + 1e-3;
+    let close = |a: Rect, b: Rect| {
+        (!a.is_finite() && !b.is_finite())
+            || [a.min - b.min, a.max - b.max]
+                .iter()
+                .all(|d| d.x.abs() <= tolerance && d.y.abs() <= tolerance)
+    };
+
+    for (expected, actual) in expected.shapes.iter().zip(&actual.shapes) {
+        assert!(close(expected.clip_rect, actual.clip_rect), "{name}: clip rect");
+        assert!(
+            close(
+                expected.shape.visual_bounding_rect(),
+                actual.shape.visual_bounding_rect()
+            ),
+            "{name}: {:?} vs {:?}",
+            expected.shape,
+            actual.shape
+        );
+    }
+}
+
+/// Runs the UI against a scripted pointer and returns each packet which would be sent
+fn record_packets(mut ui_func: Box<dyn FnMut(&Context)>) -> Vec<ServerToClient> {
+    let screen_size = Vec2::new(1280., 720.);
+    let mut harness = Harness::new(screen_size);
+
+    (0..FRAMES)
+        .map(|frame| {
+            let t = frame as f32 / 30.;
+            let pos = (screen_size / 2.).to_pos2() + Vec2::angled(t) * 200. * (t * 0.3).sin();
+
+            harness.hover(pos);
+            if frame % 40 == 0 || frame % 40 == 20 {
+                harness.event(Event::PointerButton {
+                    pos,
+                    button: PointerButton::Primary,
+                    pressed: frame % 40 == 0,
+                    modifiers: Default::default(),
+                });
+            }
+
+            harness.step(&mut ui_func);
+            harness.packet().unwrap().clone()
+        })
+        .collect()
+}
@@ -23,17 +23,7 @@ fn main() {
     loop {
         let tick_start = Instant::now();
 
-        server.for_each_client(|ctx| {
-            let demo = ctx.memory_mut(|mem| {
-                mem.data
-                    .get_temp_mut_or_insert_with(Id::new("Demo"), || {
-                        Arc::new(Mutex::new(SafeDemo(DemoWindows::default())))
-                    })
-                    .clone()
-            });
-
-            demo.lock().unwrap().0.ui(ctx);
-        });
+        server.for_each_client(ui);
 
         let desired_tickrate = 90.0;
         let tick_time = tick_start.elapsed();
@@ -41,3 +31,16 @@ fn main() {
         std::thread::sleep(std::time::Duration::from_secs_f32(remaining_time));
     }
 }
+
+/// A demo of its own for each client. Also run by the codec bench
+pub fn ui(ctx: &egui::Context) {
+    let demo = ctx.memory_mut(|mem| {
+        mem.data
+            .get_temp_mut_or_insert_with(Id::new("Demo"), || {
+                Arc::new(Mutex::new(SafeDemo(DemoWindows::default())))
+            })
+            .clone()
+    });
+
+    demo.lock().unwrap().0.ui(ctx);
+}
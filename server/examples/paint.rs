@@ -15,14 +15,7 @@ fn main() {
     loop {
         let tick_start = Instant::now();
 
-        server.for_each_client(|ctx| {
-            egui::CentralPanel::default().show(ctx, |ui| {
-                painting.ui_control(ui);
-                egui::Frame::canvas(ui.style()).show(ui, |ui| {
-                    painting.ui_content(ui);
-                });
-            });
-        });
+        server.for_each_client(|ctx| painting.ui(ctx));
 
         let tick_time = tick_start.elapsed();
         let remaining_time = (1. / desired_tickrate - tick_time.as_secs_f32()).max(0.0);
@@ -46,6 +39,16 @@ impl Default for Painting {
 }
 
 impl Painting {
+    /// Also run by the codec bench
+    pub fn ui(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            self.ui_control(ui);
+            egui::Frame::canvas(ui.style()).show(ui, |ui| {
+                self.ui_content(ui);
+            });
+        });
+    }
+
     pub fn ui_control(&mut self, ui: &mut egui::Ui) -> egui::Response {
         ui.horizontal(|ui| {
             ui.label("Stroke:");
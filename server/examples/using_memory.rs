@@ -13,10 +13,7 @@ fn main() {
 
     let mut server = Server::new("0.0.0.0:5000");
 
-    let mut counter = 0;
-    let mut drag = 0.0;
-
-    let mut user_counter: usize = 0;
+    let mut app = App::default();
 
     // We want 20 ticks per second
     let desired_tickrate = 90.0;
@@ -24,28 +21,40 @@ fn main() {
     loop {
         let tick_start = Instant::now();
 
-        server.for_each_client(|ctx| {
-            let user_number = ctx.memory_mut(|mem| {
-                *mem.data
-                    .get_temp_mut_or_insert_with(Id::new("user_number"), || {
-                        user_counter += 1;
-                        user_counter
-                    })
-            });
-
-            egui::CentralPanel::default().show(ctx, |ui| {
-                ui.label(format!("You are user #{}", user_number));
-                if ui.button(format!("Hello world! {}", counter)).clicked() {
-                    counter += 1;
-                }
-
-                ui.add(DragValue::new(&mut drag));
-                ui.add(Slider::new(&mut drag, 0.0..=1000.0));
-            });
-        });
+        server.for_each_client(|ctx| app.ui(ctx));
 
         let tick_time = tick_start.elapsed();
         let remaining_time = (1. / desired_tickrate - tick_time.as_secs_f32()).max(0.0);
         std::thread::sleep(std::time::Duration::from_secs_f32(remaining_time));
     }
 }
+
+/// Also run by the codec bench
+#[derive(Default)]
+pub struct App {
+    counter: usize,
+    drag: f32,
+    user_counter: usize,
+}
+
+impl App {
+    pub fn ui(&mut self, ctx: &egui::Context) {
+        let user_number = ctx.memory_mut(|mem| {
+            *mem.data
+                .get_temp_mut_or_insert_with(Id::new("user_number"), || {
+                    self.user_counter += 1;
+                    self.user_counter
+                })
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.label(format!("You are user #{}", user_number));
+            if ui.button(format!("Hello world! {}", self.counter)).clicked() {
+                self.counter += 1;
+            }
+
+            ui.add(DragValue::new(&mut self.drag));
+            ui.add(Slider::new(&mut self.drag, 0.0..=1000.0));
+        });
+    }
+}
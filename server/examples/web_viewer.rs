@@ -0,0 +1,32 @@
+//! Browse to http://localhost:5000 to get a viewer from the server itself.
+//! Pass a `trunk build` output directory, or build with the `web-viewer` feature.
+use meterm_server::{egui, Server, ServerConfig, WebViewer};
+
+fn main() {
+    env_logger::init();
+
+    let viewer = match std::env::args().nth(1) {
+        Some(dir) => WebViewer::from_dir(dir).expect("Loading web viewer"),
+        #[cfg(feature = "web-viewer")]
+        None => WebViewer::embedded(),
+        #[cfg(not(feature = "web-viewer"))]
+        None => panic!("Usage: web_viewer <viewer dist dir>"),
+    };
+
+    let config = ServerConfig {
+        web_viewer: Some(viewer),
+        ..Default::default()
+    };
+    let mut server = Server::with_config("0.0.0.0:5000", config);
+    let mut counter = 0;
+
+    loop {
+        server.for_each_client(|ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                if ui.button(format!("Click to increase! ({})", counter)).clicked() {
+                    counter += 1;
+                }
+            });
+        });
+    }
+}
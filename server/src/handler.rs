@@ -1,20 +1,38 @@
 use std::any::Any;
 
 use egui::Context;
-use meterm_common::{egui::{self, ahash::HashMap, FullOutput, RawInput}, ClientToServer};
+use meterm_common::{
+    egui::{
+        self,
+        ahash::{HashMap, HashSet},
+        CursorIcon, FullOutput, Id, RawInput, Rect, Vec2, WidgetRect,
+    },
+    hit_test::{HitRegion, HitRegions, HoverVisuals},
+    ClientToServer,
+};
+
+use crate::utils;
 
 #[derive(Default)]
 pub struct ClientGuiHandler {
     ctx: egui::Context,
     latest_blank_input: Option<RawInput>,
+    /// Cursor shown while each widget was hovered, for [`HitRegion::cursor`]
+    cursors: HashMap<Id, CursorIcon>,
+    /// As of the last pass, see [`crate::utils::set_preferred_size`]
+    preferred_size: Vec2,
 }
 
 impl ClientGuiHandler {
     pub fn new() -> Self {
         let ctx = Context::default();
+        // Zooming is up to the client, which tells us with `pixels_per_point`
+        ctx.options_mut(|options| options.zoom_with_keyboard = false);
         Self { 
             ctx, 
             latest_blank_input: None, 
+            cursors: HashMap::default(),
+            preferred_size: Vec2::ZERO,
         }
     }
 
@@ -23,7 +41,20 @@ impl ClientGuiHandler {
         ui_func: &mut dyn FnMut(&Context) -> (),
         packet: ClientToServer,
     ) -> Option<FullOutput> {
-        let ClientToServer { raw_input } = packet;
+        let ClientToServer {
+            mut raw_input,
+            pixels_per_point,
+            ..
+        } = packet;
+        if pixels_per_point.is_finite() && pixels_per_point > 0.0 {
+            raw_input
+                .viewports
+                .entry(raw_input.viewport_id)
+                .or_default()
+                .native_pixels_per_point = Some(pixels_per_point);
+        }
+        let resized = self.screen_rect() != raw_input.screen_rect
+            || self.pixels_per_point() != raw_input.viewport().native_pixels_per_point;
 
         // Blank input, used to send updates to clients which need updating 
         // due to activity from other clients
@@ -31,9 +62,25 @@ impl ClientGuiHandler {
         blank.events.clear();
         self.latest_blank_input = Some(blank);
 
+        let preferred_size = self.preferred_size;
         let server_to_client = self.handle_raw_input_in_ui(ui_func, raw_input);
+        let resized = resized || self.preferred_size != preferred_size;
+
+        // Nothing asks for a repaint when only a size or the pixels changed
+        (self.ctx.has_requested_repaint() || resized).then(|| server_to_client)
+    }
 
-        self.ctx.has_requested_repaint().then(|| server_to_client)
+    /// Of the last input, i.e. what the UI was last laid out for
+    pub fn screen_rect(&self) -> Option<Rect> {
+        self.latest_blank_input.as_ref()?.screen_rect
+    }
+
+    /// Of the last input, as asked for by the client
+    pub fn pixels_per_point(&self) -> Option<f32> {
+        self.latest_blank_input
+            .as_ref()?
+            .viewport()
+            .native_pixels_per_point
     }
 
     pub fn handle_blank_packet_in_ui(
@@ -45,12 +92,123 @@ impl ClientGuiHandler {
         })
     }
 
-    fn handle_raw_input_in_ui(
+    pub fn handle_raw_input_in_ui(
         &mut self,
         ui_func: &mut dyn FnMut(&Context) -> (),
         raw_input: RawInput,
     ) -> FullOutput {
-        self.ctx.run(raw_input, |ctx| ui_func(ctx))
+        let mut preferred_size = self.preferred_size;
+        let full_output = self.ctx.run(raw_input, |ctx| {
+            ui_func(ctx);
+            // Gone once the pass ends
+            preferred_size = utils::preferred_size(ctx);
+        });
+        self.preferred_size = preferred_size;
+        full_output
+    }
+
+    pub fn preferred_size(&self) -> Vec2 {
+        self.preferred_size
+    }
+
+    pub fn has_requested_repaint(&self) -> bool {
+        self.ctx.has_requested_repaint()
     }
 
+    /// Interactive widgets of the pass which produced `full_output`
+    pub fn hit_regions(&mut self, full_output: &FullOutput) -> HitRegions {
+        let hovered = self.ctx.viewport(|vp| vp.interact_widgets.hovered.clone());
+
+        let layers: Vec<_> = self.ctx.memory(|mem| mem.layer_ids().collect());
+        let layers: Vec<_> = layers
+            .into_iter()
+            .map(|layer_id| (layer_id, self.ctx.layer_transform_to_global(layer_id)))
+            .collect();
+
+        let widgets: Vec<WidgetRect> = self.ctx.viewport(|vp| {
+            let widgets = &vp.prev_pass.widgets;
+            layers
+                .iter()
+                .flat_map(|&(layer_id, transform)| {
+                    widgets.get_layer(layer_id).map(move |widget| match transform {
+                        Some(transform) => widget.transform(transform),
+                        None => *widget,
+                    })
+                })
+                .filter(|widget| {
+                    (widget.sense.senses_click() || widget.sense.senses_drag())
+                        && widget.interact_rect.is_positive()
+                })
+                .collect()
+        });
+
+        // The cursor is the doing of the topmost hovered widget
+        if let Some(widget) = widgets.iter().rev().find(|widget| hovered.contains(&widget.id)) {
+            self.cursors
+                .insert(widget.id, full_output.platform_output.cursor_icon);
+        }
+        // Forget widgets which are gone
+        let ids: HashSet<Id> = widgets.iter().map(|widget| widget.id).collect();
+        self.cursors.retain(|id, _| ids.contains(id));
+
+        let regions = widgets
+            .iter()
+            .map(|widget| HitRegion {
+                rect: widget.interact_rect,
+                // Moving or clicking a window's background doesn't light it up
+                highlight: widget.enabled
+                    && widget.sense.senses_click()
+                    && widget.id != widget.layer_id.id.with("move"),
+                cursor: self.cursors.get(&widget.id).copied(),
+                hovered: hovered.contains(&widget.id),
+            })
+            .collect();
+
+        let visuals = self.ctx.style().visuals.widgets.hovered;
+        HitRegions {
+            regions,
+            visuals: HoverVisuals {
+                stroke: visuals.bg_stroke,
+                corner_radius: visuals.corner_radius,
+                expansion: visuals.expansion,
+            },
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2, Event};
+
+    use super::*;
+
+    /// A couple of passes with the pointer over where the button is, or was
+    fn hover(handler: &mut ClientGuiHandler, button: bool) {
+        let input = RawInput {
+            screen_rect: Some(Rect::from_min_size(pos2(0.0, 0.0), vec2(200.0, 200.0))),
+            events: vec![Event::PointerMoved(pos2(20.0, 20.0))],
+            ..Default::default()
+        };
+        let mut ui = |ctx: &Context| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                if button {
+                    ui.add_sized([100.0, 100.0], egui::Button::new("Hi"));
+                }
+            });
+        };
+        for _ in 0..2 {
+            let output = handler.handle_raw_input_in_ui(&mut ui, input.clone());
+            handler.hit_regions(&output);
+        }
+    }
+
+    #[test]
+    fn forgets_cursors_of_removed_widgets() {
+        let mut handler = ClientGuiHandler::new();
+        hover(&mut handler, true);
+        assert_eq!(handler.cursors.len(), 1);
+        hover(&mut handler, false);
+        assert!(handler.cursors.is_empty());
+    }
 }
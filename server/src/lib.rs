@@ -1,57 +1,267 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use egui::{ahash::HashMap, Context, Ui};
+use egui::{ahash::HashMap, Context, FullOutput, Ui, Vec2};
 use futures_util::SinkExt;
 use futures_util::{stream::StreamExt, TryStreamExt};
 use handler::ClientGuiHandler;
 use log::{error, info, warn};
+use meterm_common::codec::Codec;
+use meterm_common::compact::ShapeEncoding;
 use meterm_common::delta_encoding::Encoder;
-use meterm_common::{delta_encoding, ClientToServer, ServerToClient};
+use meterm_common::hit_test::HitRegions;
+use meterm_common::recording::Recorder;
+use meterm_common::stats::{Meter, TrafficStats};
+use meterm_common::transport::stream::{self, StreamTransport};
+use meterm_common::transport::{Frame, Loopback, LoopbackConnector, Transport, TransportEvent};
+use meterm_common::wire::{Wire, WireFormat};
+use meterm_common::{
+    delta_encoding, ClientHello, ClientToServer, ServerHello, ServerToClient, SessionToken,
+};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::Message;
 
 pub use meterm_common::egui;
 
+pub mod testing;
 pub mod utils;
 
 mod handler;
+mod web;
+
+pub use web::WebViewer;
+
+/// Connections accepted off the UI thread, which become [`Client`]s there
+type NewTransport = Box<dyn Transport>;
+
+/// Wait after a stream listener fails to accept, doubling while it keeps failing
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 pub struct Server {
-    new_client_rx: std::sync::mpsc::Receiver<Client>,
+    new_client_tx: std::sync::mpsc::Sender<NewTransport>,
+    new_client_rx: std::sync::mpsc::Receiver<NewTransport>,
+    loopback_rx: std::sync::mpsc::Receiver<Loopback>,
+    loopback: LoopbackConnector,
+    config: ServerConfig,
     clients: Vec<Client>,
-    runtime: tokio::runtime::Runtime,
+    /// Disconnected clients, waiting to be resumed
+    suspended: HashMap<SessionToken, Suspended>,
+    runtime: Option<tokio::runtime::Runtime>,
     force_repaint: bool,
 }
 
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Codecs offered to clients, most preferred first. Each client gets the
+    /// first one it also supports, falling back to lz4. All of this build's
+    /// by default, see [`Codec::supported`]
+    pub codecs: Vec<Codec>,
+    /// Whether clients may ask for the quantized [`ShapeEncoding::Compact`]
+    pub allow_compact_shapes: bool,
+    /// Serve this viewer to plain HTTP requests on the WebSocket's port, so
+    /// browsing to e.g. `http://localhost:5000` just works
+    pub web_viewer: Option<WebViewer>,
+    /// How long the UI state of a disconnected client is kept, so it can
+    /// reconnect to the same `Context`. Zero to drop clients right away
+    pub session_grace_period: Duration,
+    /// A client which sends nothing for this long is taken to be gone, e.g.
+    /// behind a half-open socket, and its session suspended. Quiet clients
+    /// are sent an update well before then, which live ones answer
+    pub client_timeout: Duration,
+    /// Longest frame over TCP, Unix sockets and stdio, either way. Clients
+    /// may not make us allocate more than this per message
+    pub max_frame_len: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            codecs: Codec::supported(),
+            allow_compact_shapes: true,
+            web_viewer: None,
+            session_grace_period: Duration::from_secs(60),
+            client_timeout: Duration::from_secs(30),
+            max_frame_len: stream::DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+}
+
 pub struct Client {
-    rx: std::sync::mpsc::Receiver<ClientToServer>,
-    tx: tokio::sync::mpsc::Sender<ServerToClient>,
+    transport: Box<dyn Transport>,
+    config: ServerConfig,
+    /// Set once the hello was answered; until then there's no format or codec
+    wire: Option<Wire>,
+    closed: bool,
     gui_handler: ClientGuiHandler,
     encoder: delta_encoding::Encoder,
-    // TODO: task join handle here
+    recorder: Option<Recorder>,
+    /// Set by the handshake
+    session: Option<SessionToken>,
+    /// Whether the client asked for [`HitRegions`]
+    hit_regions: bool,
+    /// Last ones sent, which needn't be sent again
+    sent_hit_regions: Option<HitRegions>,
+    sent_size: Option<Vec2>,
+    meter: Meter,
+    connected_at: Instant,
+    /// For [`ServerConfig::client_timeout`]
+    last_received: Instant,
+    last_sent: Instant,
+    /// An update was skipped while the transport was backed up
+    needs_resend: bool,
+}
+
+/// What's kept of a [`Client`] while it's gone
+struct Suspended {
+    since: Instant,
+    gui_handler: ClientGuiHandler,
+    encoder: delta_encoding::Encoder,
+    recorder: Option<Recorder>,
 }
 
 impl Server {
     pub fn new(addr: impl Into<String>) -> Self {
+        Self::with_config(addr, ServerConfig::default())
+    }
+
+    pub fn with_config(addr: impl Into<String>, config: ServerConfig) -> Self {
         let (new_client_tx, new_client_rx) = std::sync::mpsc::channel();
 
         let runtime = tokio::runtime::Runtime::new().unwrap();
-        runtime.spawn(server_loop(addr.into(), new_client_tx));
+        runtime.spawn(server_loop(
+            addr.into(),
+            config.web_viewer.clone(),
+            new_client_tx.clone(),
+        ));
+
+        Self {
+            runtime: Some(runtime),
+            ..Self::from_parts(new_client_tx, new_client_rx, config)
+        }
+    }
 
+    /// A server which doesn't listen on any socket. Connections come from
+    /// [`Server::loopback_connector`], [`Server::add_transport`] or the `listen_*` methods
+    pub fn local(config: ServerConfig) -> Self {
+        let (new_client_tx, new_client_rx) = std::sync::mpsc::channel();
+        Self::from_parts(new_client_tx, new_client_rx, config)
+    }
+
+    fn from_parts(
+        new_client_tx: std::sync::mpsc::Sender<NewTransport>,
+        new_client_rx: std::sync::mpsc::Receiver<NewTransport>,
+        config: ServerConfig,
+    ) -> Self {
+        let (loopback, loopback_rx) = LoopbackConnector::new();
         Self {
-            runtime,
+            runtime: None,
+            new_client_tx,
             new_client_rx,
+            loopback_rx,
+            loopback,
+            config,
             clients: vec![],
+            suspended: HashMap::default(),
             force_repaint: false,
         }
     }
 
-    pub fn for_each_client(&mut self, mut ui_func: impl FnMut(&Context)) {
-        // Register new clients
-        self.clients.extend(self.new_client_rx.try_iter());
+    /// For connecting a viewer in the same process, e.g. `ServerWidget::loopback`
+    pub fn loopback_connector(&self) -> LoopbackConnector {
+        self.loopback.clone()
+    }
+
+    /// Serve a client over an already established connection
+    pub fn add_transport(&mut self, transport: impl Transport + 'static) {
+        self.clients
+            .push(Client::new(Box::new(transport), self.config.clone()));
+    }
+
+    /// Also accept length-prefixed connections (see `PROTOCOL.md`) on a plain TCP socket
+    pub fn listen_tcp(&self, addr: impl std::net::ToSocketAddrs) -> std::io::Result<()> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        let incoming = std::iter::repeat_with(move || listener.accept().map(|(stream, _)| stream));
+        self.spawn_listener(incoming, StreamTransport::tcp);
+        Ok(())
+    }
+
+    /// Also accept length-prefixed connections on a Unix domain socket at `path`
+    #[cfg(unix)]
+    pub fn listen_unix(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        let incoming = std::iter::repeat_with(move || listener.accept().map(|(stream, _)| stream));
+        self.spawn_listener(incoming, StreamTransport::unix);
+        Ok(())
+    }
+
+    /// Serve a single client over our stdin and stdout, e.g. when spawned by a
+    /// viewer. Logs must go to stderr then
+    pub fn serve_stdio(&mut self) {
+        let transport = StreamTransport::stdio();
+        transport.set_max_frame_len(self.config.max_frame_len);
+        self.add_transport(transport);
+    }
 
-        // Drop disconnected clients
-        self.clients.retain(|client| client.is_alive());
+    fn spawn_listener<S: Send + 'static>(
+        &self,
+        incoming: impl Iterator<Item = std::io::Result<S>> + Send + 'static,
+        into_transport: fn(S) -> std::io::Result<StreamTransport>,
+    ) {
+        let new_client_tx = self.new_client_tx.clone();
+        let max_frame_len = self.config.max_frame_len;
+        std::thread::spawn(move || {
+            let mut backoff = None;
+            for stream in incoming {
+                let stream = match stream {
+                    Ok(stream) => {
+                        backoff = None;
+                        stream
+                    }
+                    Err(e) => match accept_error(&e) {
+                        AcceptError::Connection => {
+                            warn!("Accepting stream connection; {e}");
+                            continue;
+                        }
+                        AcceptError::Listener => {
+                            error!("Stopped accepting stream connections; {e}");
+                            break;
+                        }
+                        AcceptError::Resources => {
+                            // Only warn once per streak, it may be a while
+                            if backoff.is_none() {
+                                warn!("Accepting stream connection, backing off; {e}");
+                            }
+                            let delay = backoff.map_or(MIN_ACCEPT_BACKOFF, |delay: Duration| {
+                                (delay * 2).min(MAX_ACCEPT_BACKOFF)
+                            });
+                            backoff = Some(delay);
+                            std::thread::sleep(delay);
+                            continue;
+                        }
+                    },
+                };
+
+                match into_transport(stream) {
+                    Ok(transport) => {
+                        info!("New stream connection");
+                        transport.set_max_frame_len(max_frame_len);
+                        if new_client_tx.send(Box::new(transport)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => warn!("Setting up stream connection; {e}"),
+                }
+            }
+        });
+    }
+
+    pub fn for_each_client(&mut self, mut ui_func: impl FnMut(&Context)) {
+        self.update_clients();
 
         // Handle each client
         let mut any_requested_repaint = false;
@@ -61,14 +271,132 @@ impl Server {
 
         self.force_repaint = any_requested_repaint;
     }
+
+    /// Currently connected clients, e.g. to attach a [`Recorder`]
+    pub fn clients_mut(&mut self) -> impl Iterator<Item = &mut Client> {
+        self.update_clients();
+        self.clients.iter_mut()
+    }
+
+    fn update_clients(&mut self) {
+        // Register new clients
+        for transport in self.new_client_rx.try_iter() {
+            self.clients.push(Client::new(transport, self.config.clone()));
+        }
+        for loopback in self.loopback_rx.try_iter() {
+            self.clients
+                .push(Client::new(Box::new(loopback), self.config.clone()));
+        }
+
+        // Handshakes, here where the other clients are in reach
+        for i in 0..self.clients.len() {
+            let Some((wire, hello)) = self.clients[i].read_hello() else {
+                continue;
+            };
+            let previous = hello.resume.and_then(|token| {
+                self.suspended.remove(&token).or_else(|| {
+                    // Back before the old connection was noticed to be gone,
+                    // e.g. half-open after the network dropped
+                    let old = self
+                        .clients
+                        .iter_mut()
+                        .find(|client| client.session == Some(token))?;
+                    info!("Session {token:?} reconnected, dropping its old connection");
+                    Some(old.take_session())
+                })
+            });
+            let client = &mut self.clients[i];
+            if let Err(e) = client.handshake(wire, &hello, previous) {
+                warn!("Handshake failed; {e}");
+                client.closed = true;
+            }
+        }
+
+        // Put disconnected clients aside for a while, in case they come back
+        let (alive, gone): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.clients).into_iter().partition(Client::is_alive);
+        self.clients = alive;
+        self.suspended.extend(gone.into_iter().filter_map(Client::suspend));
+
+        let grace_period = self.config.session_grace_period;
+        self.suspended
+            .retain(|_, suspended| suspended.since.elapsed() < grace_period);
+    }
+}
+
+/// A WebSocket, as seen from the UI thread. The actual socket is driven by
+/// [`accept_connection`] on the runtime
+struct WebSocketTransport {
+    rx: std::sync::mpsc::Receiver<TransportEvent>,
+    tx: tokio::sync::mpsc::Sender<Frame>,
+    subprotocol: Option<WireFormat>,
+}
+
+impl Transport for WebSocketTransport {
+    fn send(&mut self, frame: Frame) {
+        // Never wait on the socket from the UI thread. Clients check
+        // `is_backed_up` first, so only a closed socket loses anything here
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(frame) {
+            warn!("WebSocket send queue full, dropping a frame");
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        match self.rx.try_recv() {
+            Ok(event) => Some(event),
+            Err(std::sync::mpsc::TryRecvError::Empty) => None,
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => Some(TransportEvent::Closed),
+        }
+    }
+
+    fn is_backed_up(&self) -> bool {
+        self.tx.capacity() == 0
+    }
+
+    fn wire_format(&self) -> Option<WireFormat> {
+        self.subprotocol
+    }
 }
 
 async fn accept_connection(
-    stream: TcpStream,
-    tx: std::sync::mpsc::Sender<ClientToServer>,
-    mut rx: tokio::sync::mpsc::Receiver<ServerToClient>,
+    mut stream: TcpStream,
+    web_viewer: Option<WebViewer>,
+    new_client_tx: std::sync::mpsc::Sender<NewTransport>,
 ) {
-    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+    let mut head = vec![];
+    if let Some(viewer) = &web_viewer {
+        head = match web::read_head(&mut stream).await {
+            Ok(head) => head,
+            Err(e) => {
+                warn!("Reading request; {e}");
+                return;
+            }
+        };
+        if !web::is_websocket(&head) {
+            return web::serve(stream, &head, viewer).await;
+        }
+    }
+    let stream = web::Replayed::new(head, stream);
+
+    // Browsers must get their requested subprotocol echoed back
+    let mut subprotocol = None;
+    let callback = |req: &Request, mut resp: Response| {
+        subprotocol = req
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').find_map(WireFormat::from_subprotocol));
+
+        if let Some(format) = subprotocol {
+            resp.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(format.subprotocol()),
+            );
+        }
+        Ok(resp)
+    };
+
+    let mut ws_stream = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
         Ok(stream) => stream,
         Err(e) => {
             warn!("Error during the websocket handshake occurred; {e}");
@@ -78,29 +406,52 @@ async fn accept_connection(
 
     info!("New WebSocket connection");
 
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    let (frame_tx, mut frame_rx) = tokio::sync::mpsc::channel(100);
+
+    let transport = WebSocketTransport {
+        rx: event_rx,
+        tx: frame_tx,
+        subprotocol,
+    };
+    if new_client_tx.send(Box::new(transport)).is_err() {
+        return;
+    }
+
+    // Just move frames around; everything else happens in `Client`
     loop {
         tokio::select! {
             msg = ws_stream.next() => {
-                match msg {
-                    Some(Ok(Message::Binary(msg))) => tx.send(
-                        meterm_common::deserialize::<ClientToServer>(&msg).unwrap()
-                    ).unwrap(),
-                    Some(Ok(Message::Close(_))) => {
+                let event = match msg {
+                    Some(Ok(Message::Binary(msg))) => TransportEvent::Frame(Frame::Binary(msg.to_vec())),
+                    Some(Ok(Message::Text(msg))) => TransportEvent::Frame(Frame::Text(msg.to_string())),
+                    Some(Ok(Message::Close(_))) | None => {
                         info!("Graceful shutdown");
+                        let _ = event_tx.send(TransportEvent::Closed);
                         break;
                     },
                     Some(Err(e)) => {
                         warn!("Receiving from stream; {}", e);
+                        let _ = event_tx.send(TransportEvent::Error(e.to_string()));
                         break;
                     }
-                    _ => (),
+                    _ => continue,
+                };
+
+                // The server dropped this client
+                if event_tx.send(event).is_err() {
+                    break;
                 }
             },
-            Some(val) = rx.recv() => {
-                let ser = meterm_common::serialize::<ServerToClient>(&val).unwrap();
-                let _ = ws_stream.send(Message::Binary(
-                        ser.into()
-                )).await;
+            frame = frame_rx.recv() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                let msg = match frame {
+                    Frame::Binary(bytes) => Message::Binary(bytes.into()),
+                    Frame::Text(text) => Message::Text(text.into()),
+                };
+                let _ = ws_stream.send(msg).await;
             },
         }
         // Always await on at least something
@@ -108,79 +459,353 @@ async fn accept_connection(
     }
 }
 
-async fn server_loop(addr: String, new_client_tx: std::sync::mpsc::Sender<Client>) {
+/// JSON connections get no compression and standard shapes, since that's what a web client can read
+fn negotiate(
+    config: &ServerConfig,
+    hello: &ClientHello,
+    format: WireFormat,
+) -> (Codec, ShapeEncoding) {
+    match format {
+        WireFormat::Json => (Codec::None, ShapeEncoding::Standard),
+        WireFormat::Bincode => (
+            Codec::negotiate(&config.codecs, &hello.codecs),
+            if config.allow_compact_shapes {
+                hello.shape_encoding
+            } else {
+                ShapeEncoding::Standard
+            },
+        ),
+    }
+}
+
+/// 128 bits straight from the OS's CSPRNG
+fn new_session_token() -> std::io::Result<SessionToken> {
+    let mut token = [0; 16];
+    getrandom::getrandom(&mut token)?;
+    Ok(SessionToken(token))
+}
+
+/// What a failed `accept` says about the listener
+enum AcceptError {
+    /// Only the one connection is gone, e.g. reset before we got to it
+    Connection,
+    /// The listener itself is broken, so it's no use trying again
+    Listener,
+    /// Probably out of file descriptors or memory, which takes a while to get better
+    Resources,
+}
+
+fn accept_error(e: &std::io::Error) -> AcceptError {
+    use std::io::ErrorKind::*;
+    match e.kind() {
+        ConnectionAborted | ConnectionReset | Interrupted | WouldBlock | TimedOut => {
+            AcceptError::Connection
+        }
+        InvalidInput | NotConnected | Unsupported => AcceptError::Listener,
+        _ => AcceptError::Resources,
+    }
+}
+
+async fn server_loop(
+    addr: String,
+    web_viewer: Option<WebViewer>,
+    new_client_tx: std::sync::mpsc::Sender<NewTransport>,
+) {
     let try_socket = TcpListener::bind(&addr).await;
 
     let listener = try_socket.expect("Failed to bind");
 
     while let Ok((stream, _)) = listener.accept().await {
-        let (client_to_server_tx, client_to_server_rx) = std::sync::mpsc::channel();
-        let (server_to_client_tx, server_to_client_rx) = tokio::sync::mpsc::channel(100);
-
-        new_client_tx
-            .send(Client {
-                rx: client_to_server_rx,
-                tx: server_to_client_tx,
-                gui_handler: ClientGuiHandler::new(),
-                encoder: Encoder::new(),
-            })
-            .unwrap();
-
         tokio::spawn(accept_connection(
             stream,
-            client_to_server_tx,
-            server_to_client_rx,
+            web_viewer.clone(),
+            new_client_tx.clone(),
         ));
     }
 }
 
 impl Client {
-    fn handle_ctx(
-        &mut self,
-        ui_func: &mut dyn FnMut(&Context),
-        force_update: bool,
-    ) -> bool {
+    fn new(transport: Box<dyn Transport>, config: ServerConfig) -> Self {
+        Self {
+            transport,
+            config,
+            wire: None,
+            closed: false,
+            gui_handler: ClientGuiHandler::new(),
+            encoder: Encoder::new(),
+            recorder: None,
+            session: None,
+            hit_regions: false,
+            sent_hit_regions: None,
+            sent_size: None,
+            meter: Meter::new(),
+            connected_at: Instant::now(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            needs_resend: false,
+        }
+    }
+
+    fn handle_ctx(&mut self, ui_func: &mut dyn FnMut(&Context), force_update: bool) -> bool {
         let mut any_requested_repaint = false;
 
         // Update clients which updated
         let mut needs_blank_update = force_update;
-        for packet in self.rx.try_iter() {
+        while let Some(packet) = self.receive() {
             needs_blank_update = false;
+            let now = self.now();
+            self.meter.pinged(now, packet.ping, packet.pong);
+            self.record(|recorder| recorder.record_input(&packet));
+            let layout = (self.gui_handler.screen_rect(), self.gui_handler.pixels_per_point());
             if let Some(return_packet) = self.gui_handler.handle_packet_in_ui(ui_func, packet) {
+                // Everything moved, so references to the last keyframe won't help
+                if (self.gui_handler.screen_rect(), self.gui_handler.pixels_per_point()) != layout {
+                    self.encoder.request_keyframe();
+                }
                 any_requested_repaint = true;
-                let _ = self.tx.blocking_send(ServerToClient {
-                    update: self.encoder.encode(&return_packet),
-                });
+                self.send(&return_packet);
             }
         }
 
+        // Pongs can't wait for the UI to change
+        needs_blank_update |= self.meter.owes_pong();
+        // Nor can the frame a backed up client missed, once there's room again
+        needs_blank_update |= self.needs_resend && !self.transport.is_backed_up();
+
+        let quiet = self.last_received.elapsed();
+        if quiet > self.config.client_timeout {
+            warn!("Client silent for {quiet:?}, dropping the connection");
+            self.closed = true;
+            return any_requested_repaint;
+        }
+        // Live clients answer any update, so poke quiet ones
+        let keepalive = self.config.client_timeout / 3;
+        needs_blank_update |= quiet > keepalive && self.last_sent.elapsed() > keepalive;
+
         // Use an eventless version of the last raw input to generate an update
         if needs_blank_update {
             if let Some(return_packet) = self.gui_handler.handle_blank_packet_in_ui(ui_func) {
-                let _ = self.tx.blocking_send(ServerToClient {
-                    update: self.encoder.encode(&return_packet),
-                });
+                self.send(&return_packet);
             }
         }
 
         any_requested_repaint
     }
 
+    /// Next message from the client, once it got through the handshake
+    fn receive(&mut self) -> Option<ClientToServer> {
+        let wire = self.wire.as_mut()?;
+        let frame = next_frame(&mut *self.transport, &mut self.closed)?;
+        self.meter.received(frame.bytes().len());
+        self.last_received = Instant::now();
+
+        match wire.decode::<ClientToServer>(frame.bytes()) {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                warn!("Invalid message from client; {e}");
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    /// The client's first message, if it's here and we're still waiting for it
+    fn read_hello(&mut self) -> Option<(Wire, ClientHello)> {
+        if self.wire.is_some() || self.closed {
+            return None;
+        }
+        let frame = next_frame(&mut *self.transport, &mut self.closed)?;
+        self.meter.received(frame.bytes().len());
+        self.last_received = Instant::now();
+
+        let mut wire = Wire::new(self.transport.wire_format().unwrap_or(frame.format()));
+        match wire.decode::<ClientHello>(frame.bytes()) {
+            Ok(hello) => Some((wire, hello)),
+            Err(e) => {
+                warn!("Invalid client hello; {e}");
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    /// Answer the hello, picking up the `previous` state of the session it resumes
+    fn handshake(
+        &mut self,
+        mut wire: Wire,
+        hello: &ClientHello,
+        previous: Option<Suspended>,
+    ) -> meterm_common::Result<()> {
+        let resumed = hello.resume.zip(previous);
+        let is_resumed = resumed.is_some();
+        let session = match resumed {
+            Some((token, suspended)) => {
+                info!("Resuming session {token:?}");
+                self.gui_handler = suspended.gui_handler;
+                self.encoder = suspended.encoder;
+                // The client starts over with a fresh decoder
+                self.encoder.request_keyframe();
+                self.recorder = suspended.recorder;
+                token
+            }
+            None => new_session_token()?,
+        };
+        self.session = Some(session);
+        self.hit_regions = hello.hit_regions;
+
+        let (codec, shape_encoding) = negotiate(&self.config, hello, wire.format());
+        let server_hello = ServerHello {
+            codec,
+            shape_encoding,
+            session,
+            resumed: is_resumed,
+        };
+        info!(
+            "Negotiated {:?}, codec {:?}, shape encoding {:?}",
+            wire.format(),
+            server_hello.codec,
+            server_hello.shape_encoding
+        );
+
+        let reply = wire.encode(&server_hello)?;
+        self.meter.sent(reply.len());
+        self.transport.send(Frame::new(wire.format(), reply));
+        wire.apply_hello(&server_hello)?;
+
+        self.wire = Some(wire);
+        Ok(())
+    }
+
+    /// Record everything sent to this client from now on. The next update is a full one, so the
+    /// recording can be replayed on its own.
+    pub fn attach_recorder(&mut self, recorder: Recorder) {
+        self.encoder.request_keyframe();
+        self.recorder = Some(recorder);
+    }
+
+    pub fn detach_recorder(&mut self) -> Option<Recorder> {
+        self.recorder.take()
+    }
+
+    fn send(&mut self, full_output: &FullOutput) {
+        let Some(wire) = &mut self.wire else {
+            return;
+        };
+        // Skipped before encoding, so the next update is still against what
+        // the client last got and streaming codecs stay in step
+        if self.transport.is_backed_up() {
+            self.needs_resend = true;
+            return;
+        }
+
+        let hit_regions = self
+            .hit_regions
+            .then(|| self.gui_handler.hit_regions(full_output))
+            .filter(|regions| self.sent_hit_regions.as_ref() != Some(regions));
+        if hit_regions.is_some() {
+            self.sent_hit_regions = hit_regions.clone();
+        }
+
+        let size = Some(self.gui_handler.preferred_size())
+            .filter(|&size| self.sent_size != Some(size));
+        if size.is_some() {
+            self.sent_size = size;
+        }
+
+        let now = self.connected_at.elapsed().as_secs_f64();
+        let packet = ServerToClient {
+            update: self.encoder.encode(full_output),
+            hit_regions,
+            size,
+            ping: self.meter.ping(now),
+            pong: self.meter.pong(),
+        };
+        let bytes = match wire.encode_update(&packet) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Encoding update, dropping the client; {e}");
+                self.closed = true;
+                return;
+            }
+        };
+        let format = wire.format();
+        self.meter.update(&packet.update, now);
+        self.meter.sent(bytes.len());
+        self.record(|recorder| recorder.record_output(&packet));
+        self.transport.send(Frame::new(format, bytes));
+        self.last_sent = Instant::now();
+        self.needs_resend = false;
+    }
+
+    /// Round trip time, bandwidth and so on, since this client connected
+    pub fn stats(&self) -> TrafficStats {
+        self.meter.stats(self.now(), self.wire.as_ref())
+    }
+
+    /// Seconds since connecting, the clock for [`Meter`]
+    fn now(&self) -> f64 {
+        self.connected_at.elapsed().as_secs_f64()
+    }
+
+    fn record(&mut self, f: impl FnOnce(&mut Recorder) -> meterm_common::Result<()>) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = f(recorder) {
+                warn!("Recording failed, stopping; {e}");
+                self.recorder = None;
+            }
+        }
+    }
+
     fn is_alive(&self) -> bool {
-        !self.tx.is_closed()
+        !self.closed
     }
-}
 
-/*
-enum CompressionLevel {
-    /// See for example 33% of original bandwidth use and like no lag, dude
-    Normal,
-    /// See for example 28% of original bandwidth use and maybe a little lag
-    Maximum,
+    /// Only clients which got through the handshake have a session to resume
+    fn suspend(self) -> Option<(SessionToken, Suspended)> {
+        if self.config.session_grace_period.is_zero() {
+            return None;
+        }
+        let session = self.session?;
+        info!("Client disconnected, keeping session {session:?}");
+        Some((
+            session,
+            Suspended {
+                since: Instant::now(),
+                gui_handler: self.gui_handler,
+                encoder: self.encoder,
+                recorder: self.recorder,
+            },
+        ))
+    }
+
+    /// Hand the session over to a new connection, and close this one
+    fn take_session(&mut self) -> Suspended {
+        self.closed = true;
+        self.session = None;
+        Suspended {
+            since: Instant::now(),
+            gui_handler: std::mem::replace(&mut self.gui_handler, ClientGuiHandler::new()),
+            encoder: std::mem::take(&mut self.encoder),
+            recorder: self.recorder.take(),
+        }
+    }
 }
 
-struct ServerConfig {
-    // Optional compression (can get good rates with
-    compression: Option<CompressionLevel>,
+/// Next frame off the `transport`, setting `closed` when there won't be any more
+fn next_frame(transport: &mut dyn Transport, closed: &mut bool) -> Option<Frame> {
+    loop {
+        match transport.try_recv()? {
+            TransportEvent::Opened => continue,
+            TransportEvent::Frame(frame) => return Some(frame),
+            TransportEvent::Error(e) => {
+                warn!("Connection error; {e}");
+                *closed = true;
+                return None;
+            }
+            TransportEvent::Closed => {
+                *closed = true;
+                return None;
+            }
+        }
+    }
 }
-*/
@@ -0,0 +1,240 @@
+//! Driving a UI closure without any sockets or viewer, for integration tests.
+//!
+//! ```
+//! use meterm_server::{egui, testing::Harness};
+//!
+//! let mut counter = 0;
+//! let mut ui_func = |ctx: &egui::Context| {
+//!     egui::CentralPanel::default().show(ctx, |ui| {
+//!         if ui.button(format!("Clicked {counter}")).clicked() {
+//!             counter += 1;
+//!         }
+//!     });
+//! };
+//!
+//! let mut harness = Harness::new(egui::vec2(400., 300.));
+//! harness.run(&mut ui_func);
+//! harness.click_text("Clicked 0").unwrap();
+//! harness.run(&mut ui_func);
+//! assert!(harness.contains_text("Clicked 1"));
+//! ```
+use meterm_common::{
+    delta_encoding::{Decoder, Encoder},
+    egui::{
+        self, epaint::ClippedShape, Context, Event, FullOutput, Key, Modifiers, PointerButton,
+        Pos2, RawInput, Rect, Shape, Vec2,
+    },
+    ServerToClient,
+};
+
+use crate::handler::ClientGuiHandler;
+
+/// Endless animations would otherwise keep [`Harness::run`] going forever
+const MAX_SETTLE_FRAMES: usize = 10;
+
+/// A single simulated client. Every frame goes through the same encoding and
+/// serialization as over the network, so what the queries see is what a
+/// viewer would draw.
+pub struct Harness {
+    gui_handler: ClientGuiHandler,
+    encoder: Encoder,
+    decoder: Decoder,
+    screen_rect: Rect,
+    time: f64,
+    modifiers: Modifiers,
+    /// Sent with the next frame
+    events: Vec<Event>,
+    latest_packet: Option<ServerToClient>,
+    latest_frame: Option<FullOutput>,
+}
+
+impl Harness {
+    pub fn new(size: Vec2) -> Self {
+        Self {
+            gui_handler: ClientGuiHandler::new(),
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+            screen_rect: Rect::from_min_size(Pos2::ZERO, size),
+            time: 0.0,
+            modifiers: Modifiers::NONE,
+            events: vec![],
+            latest_packet: None,
+            latest_frame: None,
+        }
+    }
+
+    /// Run one frame with whatever input was queued since the last one, then
+    /// keep running frames (as the server would) until the UI stops asking for
+    /// repaints, so that the effects of the input are visible.
+    pub fn run(&mut self, ui_func: &mut dyn FnMut(&Context)) {
+        self.step(ui_func);
+        for _ in 0..MAX_SETTLE_FRAMES {
+            if !self.gui_handler.has_requested_repaint() {
+                break;
+            }
+            self.step(ui_func);
+        }
+    }
+
+    /// Run exactly one frame with whatever input was queued since the last one
+    pub fn step(&mut self, ui_func: &mut dyn FnMut(&Context)) {
+        const DT: f32 = 1. / 60.;
+        self.time += DT as f64;
+
+        let raw_input = RawInput {
+            screen_rect: Some(self.screen_rect),
+            time: Some(self.time),
+            predicted_dt: DT,
+            modifiers: self.modifiers,
+            events: std::mem::take(&mut self.events),
+            focused: true,
+            ..Default::default()
+        };
+
+        let full_output = self.gui_handler.handle_raw_input_in_ui(ui_func, raw_input);
+
+        let packet = ServerToClient {
+            update: self.encoder.encode(&full_output),
+            hit_regions: None,
+            size: None,
+            ping: None,
+            pong: None,
+        };
+        let bytes = meterm_common::serialize(&packet).expect("Serialize");
+        let packet: ServerToClient = meterm_common::deserialize(&bytes).expect("Deserialize");
+
+        if let Some(full_output) = self.decoder.decode(packet.update.clone()) {
+            self.latest_frame = Some(full_output);
+        }
+        self.latest_packet = Some(packet);
+    }
+
+    /// Run `frames` frames, e.g. to let an animation play out
+    pub fn run_frames(&mut self, frames: usize, ui_func: &mut dyn FnMut(&Context)) {
+        for _ in 0..frames {
+            self.step(ui_func);
+        }
+    }
+
+    pub fn set_size(&mut self, size: Vec2) {
+        self.screen_rect = Rect::from_min_size(Pos2::ZERO, size);
+    }
+
+    /// Queue a raw event for the next frame
+    pub fn event(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn hover(&mut self, pos: Pos2) {
+        self.event(Event::PointerMoved(pos));
+    }
+
+    /// Press and release the primary button at `pos`
+    pub fn click_at(&mut self, pos: Pos2) {
+        self.hover(pos);
+        for pressed in [true, false] {
+            self.event(Event::PointerButton {
+                pos,
+                button: PointerButton::Primary,
+                pressed,
+                modifiers: self.modifiers,
+            });
+        }
+    }
+
+    /// Click the middle of the first text containing `needle`. Returns where
+    /// it clicked, or `None` if no such text was drawn in the last frame.
+    pub fn click_text(&mut self, needle: &str) -> Option<Pos2> {
+        let pos = self.find_text(needle)?.center();
+        self.click_at(pos);
+        Some(pos)
+    }
+
+    /// Type into whatever has focus
+    pub fn type_text(&mut self, text: impl Into<String>) {
+        self.event(Event::Text(text.into()));
+    }
+
+    pub fn key_press(&mut self, key: Key) {
+        for pressed in [true, false] {
+            self.event(Event::Key {
+                key,
+                physical_key: None,
+                pressed,
+                repeat: false,
+                modifiers: self.modifiers,
+            });
+        }
+    }
+
+    /// Modifiers held for all following input
+    pub fn set_modifiers(&mut self, modifiers: Modifiers) {
+        self.modifiers = modifiers;
+    }
+
+    /// The last frame as it went over the wire, i.e. before delta decoding
+    pub fn packet(&self) -> Option<&ServerToClient> {
+        self.latest_packet.as_ref()
+    }
+
+    /// The last frame, as a viewer would have decoded it
+    pub fn frame(&self) -> Option<&FullOutput> {
+        self.latest_frame.as_ref()
+    }
+
+    pub fn shapes(&self) -> &[ClippedShape] {
+        self.latest_frame
+            .as_ref()
+            .map(|frame| frame.shapes.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Every text drawn in the last frame, with its bounds
+    pub fn texts(&self) -> Vec<(String, Rect)> {
+        let mut texts = vec![];
+        for ClippedShape { shape, .. } in self.shapes() {
+            collect_texts(shape, &mut texts);
+        }
+        texts
+    }
+
+    pub fn find_text(&self, needle: &str) -> Option<Rect> {
+        self.texts()
+            .into_iter()
+            .find(|(text, _)| text.contains(needle))
+            .map(|(_, rect)| rect)
+    }
+
+    pub fn contains_text(&self, needle: &str) -> bool {
+        self.find_text(needle).is_some()
+    }
+
+    /// Visible bounds of each shape, i.e. clipped. Shapes which are clipped
+    /// away entirely are left out.
+    pub fn shape_bounds(&self) -> Vec<Rect> {
+        self.shapes()
+            .iter()
+            .map(|ClippedShape { clip_rect, shape }| {
+                shape.visual_bounding_rect().intersect(*clip_rect)
+            })
+            .filter(|rect| rect.is_positive())
+            .collect()
+    }
+}
+
+fn collect_texts(shape: &Shape, texts: &mut Vec<(String, Rect)>) {
+    match shape {
+        Shape::Text(text) => texts.push((
+            text.galley.text().to_string(),
+            text.galley.rect.translate(text.pos.to_vec2()),
+        )),
+        Shape::Vec(shapes) => shapes.iter().for_each(|shape| collect_texts(shape, texts)),
+        _ => (),
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new(egui::vec2(800., 600.))
+    }
+}
@@ -1,4 +1,18 @@
-use meterm_common::egui::{Context, Id};
+use meterm_common::egui::{Context, Id, Vec2};
+
+const PREFERRED_SIZE_ID: &str = "_preferred_size_util";
+
+/// Ask auto-sizing clients (see `ServerWidget::auto_size`) for this much room,
+/// instead of whatever panels and windows used. Sticks until set again
+pub fn set_preferred_size(ctx: &Context, size: Vec2) {
+    ctx.data_mut(|writer| writer.insert_temp(Id::new(PREFERRED_SIZE_ID), size));
+}
+
+/// Only meaningful during a pass
+pub(crate) fn preferred_size(ctx: &Context) -> Vec2 {
+    ctx.data(|reader| reader.get_temp(Id::new(PREFERRED_SIZE_ID)))
+        .unwrap_or_else(|| ctx.used_size())
+}
 
 #[derive(Default)]
 pub struct ClientIdentifier {
@@ -0,0 +1,392 @@
+//! Serving the web viewer to plain HTTP requests on the WebSocket's port, so
+//! browsers need nothing but the server's address.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::warn;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+/// Requests with larger heads are refused
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Connections which take longer to send a request head are dropped
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Static files of a web viewer, i.e. the output of `trunk build` for a
+/// viewer built on `meterm-client`. The viewer has to take the server's
+/// address from the `srv` query parameter, like meterm-viewer does.
+#[derive(Clone, Default)]
+pub struct WebViewer {
+    files: Arc<HashMap<String, Cow<'static, [u8]>>>,
+}
+
+#[cfg(feature = "web-viewer")]
+mod embedded {
+    // Generated by build.rs from $METERM_VIEWER_DIST
+    include!(concat!(env!("OUT_DIR"), "/web_viewer_files.rs"));
+}
+
+impl WebViewer {
+    /// The bundle compiled into the server, see the `web-viewer` feature
+    #[cfg(feature = "web-viewer")]
+    pub fn embedded() -> Self {
+        let files = embedded::FILES
+            .iter()
+            .map(|(path, bytes)| (path.to_string(), Cow::Borrowed(*bytes)))
+            .collect();
+        Self {
+            files: Arc::new(files),
+        }
+    }
+
+    /// Load a bundle at runtime instead, e.g. while working on the viewer
+    pub fn from_dir(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut files = HashMap::new();
+        read_dir_recursive(dir.as_ref(), "", &mut files)?;
+        Ok(Self {
+            files: Arc::new(files),
+        })
+    }
+
+    fn get(&self, path: &str) -> Option<&[u8]> {
+        self.files.get(path).map(|bytes| bytes.as_ref())
+    }
+}
+
+impl fmt::Debug for WebViewer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.files.keys()).finish()
+    }
+}
+
+fn read_dir_recursive(
+    dir: &Path,
+    prefix: &str,
+    files: &mut HashMap<String, Cow<'static, [u8]>>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            read_dir_recursive(&entry.path(), &format!("{name}/"), files)?;
+        } else {
+            files.insert(name, Cow::Owned(std::fs::read(entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+/// Read up to the end of the request head, or [`MAX_HEAD_LEN`] bytes of it.
+/// Gives up after [`HEAD_TIMEOUT`], so clients trickling in a request don't
+/// hold a task
+pub(crate) async fn read_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    tokio::time::timeout(HEAD_TIMEOUT, read_head_untimed(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Request head timed out"))?
+}
+
+async fn read_head_untimed(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_HEAD_LEN];
+    let mut len = 0;
+    loop {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        len += n;
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        match httparse::Request::new(&mut headers).parse(&buf[..len]) {
+            Ok(httparse::Status::Partial) if len < buf.len() => continue,
+            _ => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+/// Whether `head` is a WebSocket upgrade, as opposed to a plain HTTP request
+pub(crate) fn is_websocket(head: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => req.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("upgrade")
+                && String::from_utf8_lossy(header.value).eq_ignore_ascii_case("websocket")
+        }),
+        // Let the WebSocket handshake complain about it
+        _ => true,
+    }
+}
+
+/// A stream with what was already read of it put back in front, so the
+/// WebSocket handshake gets to read the request head again
+pub(crate) struct Replayed {
+    head: Vec<u8>,
+    read: usize,
+    stream: TcpStream,
+}
+
+impl Replayed {
+    pub(crate) fn new(head: Vec<u8>, stream: TcpStream) -> Self {
+        Self {
+            head,
+            read: 0,
+            stream,
+        }
+    }
+}
+
+impl AsyncRead for Replayed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.head.len() {
+            let rest = &this.head[this.read..];
+            let n = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..n]);
+            this.read += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Replayed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Answer a single GET, whose head was read already, then close the connection
+pub(crate) async fn serve(mut stream: TcpStream, head: &[u8], viewer: &WebViewer) {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    let (response, head_request) = match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => (respond(&req, viewer), req.method == Some("HEAD")),
+        _ => (Response::status("400 Bad Request"), false),
+    };
+
+    if let Err(e) = response.write(&mut stream, head_request).await {
+        warn!("Serving web viewer; {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+fn respond(req: &httparse::Request, viewer: &WebViewer) -> Response {
+    let (Some(method), Some(target)) = (req.method, req.path) else {
+        return Response::status("400 Bad Request");
+    };
+    if method != "GET" && method != "HEAD" {
+        return Response::status("405 Method Not Allowed");
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    // Point the viewer back at us
+    if path == "/" && !query.split('&').any(|param| param.starts_with("srv=")) {
+        let header = |name: &str| {
+            req.headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .and_then(|header| std::str::from_utf8(header.value).ok())
+        };
+        let Some(host) = header("host").filter(|host| is_authority(host)) else {
+            return Response::status("400 Bad Request");
+        };
+        let scheme = match header("x-forwarded-proto") {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => "wss",
+            _ => "ws",
+        };
+        let srv = percent_encode(&format!("{scheme}://{host}"));
+        return Response {
+            status: "302 Found",
+            headers: vec![("Location", format!("/?srv={srv}"))],
+            body: vec![],
+        };
+    }
+
+    let file = match path.trim_start_matches('/') {
+        "" => "index.html",
+        file => file,
+    };
+    let Some(body) = viewer.get(file) else {
+        return Response::status("404 Not Found");
+    };
+
+    Response {
+        status: "200 OK",
+        headers: vec![("Content-Type", content_type(file).to_string())],
+        body: body.to_vec(),
+    }
+}
+
+/// A host name or IP address (v6 in brackets), with an optional port. No user
+/// info, path or anything else, which would let a request point the viewer elsewhere
+fn is_authority(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b))
+}
+
+/// For a query parameter's value
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn content_type(file: &str) -> &'static str {
+    match file.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        // Browsers only stream-compile wasm served as such
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn status(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "text/plain".to_string())],
+            body: status.as_bytes().to_vec(),
+        }
+    }
+
+    /// Answering a HEAD request, the body is left out but still counted
+    async fn write(&self, stream: &mut TcpStream, head_request: bool) -> std::io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head += &format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        );
+
+        stream.write_all(head.as_bytes()).await?;
+        if !head_request {
+            stream.write_all(&self.body).await?;
+        }
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(head: &str) -> Response {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        req.parse(head.as_bytes()).unwrap();
+        respond(&req, &WebViewer::default())
+    }
+
+    fn location(response: &Response) -> Option<&str> {
+        let (_, value) = response.headers.iter().find(|(name, _)| *name == "Location")?;
+        Some(value)
+    }
+
+    #[test]
+    fn redirects_to_self() {
+        let response = get("GET / HTTP/1.1\r\nHost: [::1]:5000\r\n\r\n");
+        assert_eq!(location(&response), Some("/?srv=ws%3A%2F%2F%5B%3A%3A1%5D%3A5000"));
+
+        let response =
+            get("GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-Proto: https\r\n\r\n");
+        assert_eq!(location(&response), Some("/?srv=wss%3A%2F%2Fexample.com"));
+    }
+
+    #[test]
+    fn refuses_odd_hosts() {
+        for host in ["evil.com/x", "a@evil.com", "a&srv=wss://evil.com", "a#b", "a b", ""] {
+            let response = get(&format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n"));
+            assert_eq!(response.status, "400 Bad Request", "{host}");
+        }
+    }
+
+    #[test]
+    fn websocket_or_not() {
+        assert!(is_websocket(
+            b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+        ));
+        assert!(!is_websocket(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+    }
+
+    /// One byte at a time, which used to be polled for
+    #[tokio::test]
+    async fn trickled_head() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            for byte in b"GET / HTTP/1.1\r\nHost: a\r\n\r\nrest" {
+                stream.write_all(&[*byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            stream
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let head = read_head(&mut stream).await.unwrap();
+        assert!(head.starts_with(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(!is_websocket(&head));
+
+        // Whatever was read is read again
+        let _client = client.await.unwrap();
+        let mut replayed = Replayed::new(head, stream);
+        let mut buf = vec![0; 31];
+        replayed.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\nHost: a\r\n\r\nrest");
+    }
+}
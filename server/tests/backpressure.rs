@@ -0,0 +1,103 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use meterm_common::{
+    codec::Codec,
+    compact::ShapeEncoding,
+    transport::{Frame, Loopback, Transport, TransportEvent},
+    wire::{Wire, WireFormat},
+    ClientHello, ClientToServer, ServerHello,
+};
+use meterm_server::{egui, Server, ServerConfig};
+
+/// The server's end of a loopback, backed up whenever the test says so
+struct Gate {
+    inner: Loopback,
+    backed_up: Arc<AtomicBool>,
+}
+
+impl Transport for Gate {
+    fn send(&mut self, frame: Frame) {
+        assert!(!self.is_backed_up(), "Sent while backed up");
+        self.inner.send(frame);
+    }
+
+    fn try_recv(&mut self) -> Option<TransportEvent> {
+        self.inner.try_recv()
+    }
+
+    fn is_backed_up(&self) -> bool {
+        self.backed_up.load(Ordering::Relaxed)
+    }
+}
+
+fn frames(transport: &mut Loopback) -> usize {
+    std::iter::from_fn(|| transport.try_recv())
+        .filter(|event| matches!(event, TransportEvent::Frame(_)))
+        .count()
+}
+
+#[test]
+fn resends_once_no_longer_backed_up() {
+    let (mut client, inner) = Loopback::pair();
+    let backed_up = Arc::new(AtomicBool::new(false));
+    let mut server = Server::local(ServerConfig::default());
+    server.add_transport(Gate {
+        inner,
+        backed_up: backed_up.clone(),
+    });
+
+    let mut counter = 0;
+    let mut ui = |ctx: &egui::Context| {
+        counter += 1;
+        egui::CentralPanel::default().show(ctx, |ui| ui.label(format!("Pass {counter}")));
+        ctx.request_repaint();
+    };
+
+    let mut wire = Wire::new(WireFormat::Bincode);
+    let hello = ClientHello {
+        codecs: vec![Codec::Lz4],
+        shape_encoding: ShapeEncoding::Standard,
+        resume: None,
+        hit_regions: false,
+    };
+    client.send(Frame::new(wire.format(), wire.encode(&hello).unwrap()));
+    server.for_each_client(&mut ui);
+    let hello: ServerHello = loop {
+        if let Some(TransportEvent::Frame(frame)) = client.try_recv() {
+            break wire.decode(frame.bytes()).unwrap();
+        }
+    };
+    wire.apply_hello(&hello).unwrap();
+
+    let input = ClientToServer {
+        raw_input: Default::default(),
+        pixels_per_point: 1.0,
+        ping: None,
+        pong: None,
+    };
+    let mut send_input = |client: &mut Loopback| {
+        let bytes = wire.encode(&input).unwrap();
+        client.send(Frame::new(wire.format(), bytes));
+    };
+
+    send_input(&mut client);
+    server.for_each_client(&mut ui);
+    assert_eq!(frames(&mut client), 1);
+
+    // The update for this is skipped
+    backed_up.store(true, Ordering::Relaxed);
+    send_input(&mut client);
+    server.for_each_client(&mut ui);
+    server.for_each_client(&mut ui);
+    assert_eq!(frames(&mut client), 0);
+
+    // And made up for without any further input
+    backed_up.store(false, Ordering::Relaxed);
+    server.for_each_client(&mut ui);
+    assert_eq!(frames(&mut client), 1);
+    server.for_each_client(&mut ui);
+    assert_eq!(frames(&mut client), 0);
+}
@@ -0,0 +1,138 @@
+use meterm_server::{
+    egui::{self, pos2, vec2, Color32, Key, LayerId, Rect},
+    testing::Harness,
+};
+
+#[derive(Default)]
+struct Form {
+    counter: usize,
+    name: String,
+    submitted: Option<String>,
+}
+
+impl Form {
+    fn ui(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if ui
+                .button(format!("Click to increase! ({})", self.counter))
+                .clicked()
+            {
+                self.counter += 1;
+            }
+
+            let resp = ui.text_edit_singleline(&mut self.name);
+            if resp.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                self.submitted = Some(self.name.clone());
+            }
+
+            if let Some(submitted) = &self.submitted {
+                ui.label(format!("Hello, {submitted}!"));
+            }
+        });
+    }
+}
+
+#[test]
+fn click_text() {
+    let mut form = Form::default();
+    let mut harness = Harness::new(vec2(400., 300.));
+    harness.run(&mut |ctx| form.ui(ctx));
+
+    for i in 0..3 {
+        harness
+            .click_text(&format!("({i})"))
+            .expect("Button should be drawn");
+        harness.run(&mut |ctx| form.ui(ctx));
+    }
+
+    assert_eq!(form.counter, 3);
+    assert!(harness.contains_text("Click to increase! (3)"));
+}
+
+#[test]
+fn click_at() {
+    let mut form = Form::default();
+    let mut harness = Harness::new(vec2(400., 300.));
+    harness.run(&mut |ctx| form.ui(ctx));
+
+    // Next to the button misses
+    let button = harness.find_text("Click to increase!").unwrap();
+    harness.click_at(pos2(button.max.x + 100., button.center().y));
+    harness.run(&mut |ctx| form.ui(ctx));
+    assert_eq!(form.counter, 0);
+
+    harness.click_at(button.center());
+    harness.run(&mut |ctx| form.ui(ctx));
+    assert_eq!(form.counter, 1);
+}
+
+#[test]
+fn type_text_and_key_press() {
+    let mut form = Form::default();
+    let mut harness = Harness::new(vec2(400., 300.));
+    harness.run(&mut |ctx| form.ui(ctx));
+
+    // Focus the text edit, which is drawn below the button
+    let button = harness.find_text("Click to increase!").unwrap();
+    harness.click_at(button.center() + vec2(0., 30.));
+    harness.run(&mut |ctx| form.ui(ctx));
+
+    harness.type_text("meterm");
+    harness.run(&mut |ctx| form.ui(ctx));
+    assert_eq!(form.name, "meterm");
+    assert!(harness.contains_text("meterm"));
+    assert!(form.submitted.is_none());
+
+    harness.key_press(Key::Enter);
+    harness.run(&mut |ctx| form.ui(ctx));
+    assert_eq!(form.submitted.as_deref(), Some("meterm"));
+    assert!(harness.contains_text("Hello, meterm!"));
+}
+
+#[test]
+fn find_text() {
+    let mut form = Form::default();
+    let mut harness = Harness::new(vec2(400., 300.));
+    assert!(harness.find_text("Click").is_none(), "Nothing drawn yet");
+
+    harness.run(&mut |ctx| form.ui(ctx));
+    let rect = harness.find_text("increase").unwrap();
+    assert!(rect.is_positive());
+    assert!(Rect::from_min_size(pos2(0., 0.), vec2(400., 300.)).contains_rect(rect));
+    assert!(harness.find_text("Hello").is_none());
+}
+
+#[test]
+fn shape_bounds() {
+    let shown = Rect::from_min_size(pos2(10., 20.), vec2(30., 40.));
+    let clip = Rect::from_min_size(pos2(100., 100.), vec2(10., 10.));
+    let mut ui_func = |ctx: &egui::Context| {
+        let painter = ctx.layer_painter(LayerId::background());
+        painter.rect_filled(shown, 0.0, Color32::RED);
+
+        let clipped = painter.with_clip_rect(clip);
+        // Half in
+        clipped.rect_filled(
+            Rect::from_min_size(pos2(95., 95.), vec2(10., 10.)),
+            0.0,
+            Color32::GREEN,
+        );
+        // All out
+        clipped.rect_filled(
+            Rect::from_min_size(pos2(0., 0.), vec2(10., 10.)),
+            0.0,
+            Color32::BLUE,
+        );
+    };
+
+    let mut harness = Harness::new(vec2(400., 300.));
+    harness.run(&mut ui_func);
+
+    assert_eq!(
+        harness.shape_bounds(),
+        vec![
+            shown,
+            Rect::from_min_max(pos2(100., 100.), pos2(105., 105.))
+        ]
+    );
+}
@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+use meterm_common::{
+    codec::Codec,
+    compact::ShapeEncoding,
+    transport::{Frame, Loopback, Transport, TransportEvent},
+    wire::{Wire, WireFormat},
+    ClientHello, ClientToServer, ServerHello, SessionToken,
+};
+use meterm_server::{egui, Server, ServerConfig};
+
+fn ui(ctx: &egui::Context) {
+    egui::CentralPanel::default().show(ctx, |ui| ui.label("Hi"));
+}
+
+/// A client speaking the protocol by hand
+struct Peer {
+    transport: Loopback,
+    wire: Wire,
+    hello: ServerHello,
+}
+
+impl Peer {
+    fn connect(server: &mut Server, resume: Option<SessionToken>) -> Self {
+        let mut transport = server.loopback_connector().connect();
+        let mut wire = Wire::new(WireFormat::Bincode);
+        let hello = ClientHello {
+            codecs: Codec::supported(),
+            shape_encoding: ShapeEncoding::Standard,
+            resume,
+            hit_regions: false,
+        };
+        transport.send(Frame::new(wire.format(), wire.encode(&hello).unwrap()));
+        server.for_each_client(ui);
+
+        let frame = next_frame(&mut transport).expect("Server hello");
+        let hello: ServerHello = wire.decode(frame.bytes()).unwrap();
+        wire.apply_hello(&hello).unwrap();
+        let mut peer = Self {
+            transport,
+            wire,
+            hello,
+        };
+        peer.input(server);
+        peer
+    }
+
+    fn input(&mut self, server: &mut Server) {
+        let packet = ClientToServer {
+            raw_input: Default::default(),
+            pixels_per_point: 1.0,
+            ping: None,
+            pong: None,
+        };
+        let bytes = self.wire.encode(&packet).unwrap();
+        self.transport.send(Frame::new(self.wire.format(), bytes));
+        server.for_each_client(ui);
+    }
+
+    /// Whether the server hung up, after reading whatever it sent
+    fn closed(&mut self) -> bool {
+        loop {
+            match self.transport.try_recv() {
+                Some(TransportEvent::Closed) => return true,
+                Some(_) => (),
+                None => return false,
+            }
+        }
+    }
+}
+
+fn next_frame(transport: &mut Loopback) -> Option<Frame> {
+    loop {
+        match transport.try_recv()? {
+            TransportEvent::Opened => (),
+            TransportEvent::Frame(frame) => return Some(frame),
+            event => panic!("Expected a frame, got {event:?}"),
+        }
+    }
+}
+
+/// E.g. after the network dropped, before the old connection times out
+#[test]
+fn reconnect_takes_over_live_session() {
+    let mut server = Server::local(ServerConfig::default());
+    let mut old = Peer::connect(&mut server, None);
+    assert!(!old.hello.resumed);
+
+    let new = Peer::connect(&mut server, Some(old.hello.session));
+    assert!(new.hello.resumed);
+    assert_eq!(new.hello.session, old.hello.session);
+    assert!(old.closed());
+    assert_eq!(server.clients_mut().count(), 1);
+}
+
+#[test]
+fn unknown_session_starts_over() {
+    let mut server = Server::local(ServerConfig::default());
+    let first = Peer::connect(&mut server, None);
+    let other = Peer::connect(&mut server, Some(SessionToken([7; 16])));
+    assert!(!other.hello.resumed);
+    assert_ne!(other.hello.session, first.hello.session);
+    assert_eq!(server.clients_mut().count(), 2);
+}
+
+#[test]
+fn silent_client_times_out() {
+    let mut server = Server::local(ServerConfig {
+        client_timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let start = Instant::now();
+    let mut peer = Peer::connect(&mut server, None);
+    while next_frame(&mut peer.transport).is_some() {}
+
+    // Poked once it's been quiet a while
+    let mut poked = false;
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(5), "Never timed out");
+        server.for_each_client(ui);
+        match peer.transport.try_recv() {
+            Some(TransportEvent::Frame(_)) => poked = true,
+            Some(TransportEvent::Closed) => break,
+            _ => (),
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(poked);
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    // Its session is kept
+    let back = Peer::connect(&mut server, Some(peer.hello.session));
+    assert!(back.hello.resumed);
+}
+
+#[test]
+fn answering_keeps_client_alive() {
+    let mut server = Server::local(ServerConfig {
+        client_timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let mut peer = Peer::connect(&mut server, None);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(900) {
+        if next_frame(&mut peer.transport).is_some() {
+            peer.input(&mut server);
+        }
+        server.for_each_client(ui);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!peer.closed());
+}
@@ -0,0 +1,343 @@
+//! Viewer for meterm servers, native or on the web.
+//!
+//! Usage: `meterm-viewer [ws://host:port]`. On the web, the server's address
+//! is taken from the `srv` query parameter; build with `trunk build --release`
+//! in this directory.
+use eframe::egui;
+use meterm_client::{Backoff, ConnectionStats, ConnectionStatus, ScaleMode, ServerWidget};
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> eframe::Result<()> {
+    env_logger::init();
+
+    let url = std::env::args().nth(1);
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([800., 600.]),
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        "meterm",
+        options,
+        Box::new(|cc| Ok(Box::new(ViewerApp::new(cc, url)))),
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    use wasm_bindgen::JsCast;
+
+    eframe::WebLogger::init(log::LevelFilter::Info).ok();
+
+    let window = web_sys::window().expect("No window");
+    let url = window
+        .location()
+        .search()
+        .ok()
+        .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok())
+        .and_then(|params| params.get("srv"));
+    let canvas = window
+        .document()
+        .expect("No document")
+        .get_element_by_id("the_canvas_id")
+        .expect("No canvas in index.html")
+        .dyn_into::<web_sys::HtmlCanvasElement>()
+        .expect("the_canvas_id isn't a canvas");
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let result = eframe::WebRunner::new()
+            .start(
+                canvas,
+                eframe::WebOptions::default(),
+                Box::new(|cc| Ok(Box::new(ViewerApp::new(cc, url)))),
+            )
+            .await;
+        if let Err(e) = result {
+            log::error!("Failed to start the viewer; {e:?}");
+        }
+    });
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedServer {
+    name: String,
+    url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct ViewerApp {
+    saved: Vec<SavedServer>,
+    /// Reopened on the next start, unless a URL is given
+    current: Option<String>,
+    debug_packets: bool,
+    show_stats: bool,
+    zoom: f32,
+    /// As of the last frame
+    #[serde(skip)]
+    status: Option<ConnectionStatus>,
+    #[serde(skip)]
+    url_edit: String,
+    #[serde(skip)]
+    name_edit: String,
+}
+
+impl Default for ViewerApp {
+    fn default() -> Self {
+        Self {
+            saved: vec![],
+            current: None,
+            debug_packets: false,
+            show_stats: false,
+            zoom: 1.0,
+            status: None,
+            url_edit: "ws://localhost:5000".into(),
+            name_edit: String::new(),
+        }
+    }
+}
+
+impl ViewerApp {
+    fn new(cc: &eframe::CreationContext<'_>, url: Option<String>) -> Self {
+        let mut app: Self = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+
+        if url.is_some() {
+            app.current = url;
+        }
+        if let Some(url) = &app.current {
+            app.url_edit = url.clone();
+        }
+
+        app
+    }
+
+    fn widget(&self, url: &str) -> ServerWidget {
+        ServerWidget::new(url)
+            .debug_packets(self.debug_packets)
+            .show_stats(self.show_stats)
+            .scale(ScaleMode::Zoom(self.zoom))
+            .auto_reconnect(Backoff::default())
+            .predict_hover(true)
+            .lock_focus(true)
+    }
+
+    fn servers_menu(&mut self, ui: &mut egui::Ui) {
+        let mut connect = None;
+        let mut remove = None;
+
+        if self.saved.is_empty() {
+            ui.weak("No saved servers");
+        }
+        for (idx, server) in self.saved.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.button(&server.name).on_hover_text(&server.url).clicked() {
+                    connect = Some(server.url.clone());
+                }
+                if ui.small_button("🗑").on_hover_text("Forget").clicked() {
+                    remove = Some(idx);
+                }
+            });
+        }
+
+        if let Some(idx) = remove {
+            self.saved.remove(idx);
+        }
+        if let Some(url) = connect {
+            self.current = Some(url);
+            ui.close_menu();
+        }
+
+        ui.separator();
+
+        if let Some(url) = self.current.clone() {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.name_edit).hint_text("Name"));
+                let can_save = !self.name_edit.trim().is_empty();
+                if ui.add_enabled(can_save, egui::Button::new("Save current")).clicked() {
+                    self.saved.push(SavedServer {
+                        name: std::mem::take(&mut self.name_edit).trim().to_string(),
+                        url,
+                    });
+                }
+            });
+        }
+
+        if ui.button("Connect to…").clicked() {
+            self.current = None;
+            ui.close_menu();
+        }
+    }
+
+    fn view_menu(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.debug_packets, "Debug packets")
+            .on_hover_text("Only draw shapes which were sent anew, hiding those reused from the last keyframe");
+        ui.checkbox(&mut self.show_stats, "Stats overlay");
+        ui.add(egui::Slider::new(&mut self.zoom, 0.25..=4.0).logarithmic(true).text("Zoom"));
+
+        let stats = self
+            .current
+            .as_ref()
+            .and_then(|url| self.widget(url).stats(ui.ctx()));
+        ui.menu_button("Connection stats", |ui| match stats {
+            Some(stats) => stats_ui(ui, &stats),
+            None => {
+                ui.weak("Not connected");
+            }
+        });
+    }
+
+    fn connect_ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical_centered(|ui| {
+            ui.add_space(ui.available_height() / 3.);
+            ui.heading("Connect to a meterm server");
+
+            let resp = ui.add(egui::TextEdit::singleline(&mut self.url_edit).desired_width(300.));
+            let entered = resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("Connect").clicked() || entered {
+                self.current = Some(self.url_edit.trim().to_string());
+            }
+
+            if !self.saved.is_empty() {
+                ui.add_space(10.);
+                ui.label("Saved servers");
+                for server in &self.saved {
+                    if ui.button(&server.name).on_hover_text(&server.url).clicked() {
+                        self.current = Some(server.url.clone());
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl eframe::App for ViewerApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        egui::TopBottomPanel::top("menu").show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                ui.menu_button("Servers", |ui| self.servers_menu(ui));
+                ui.menu_button("View", |ui| self.view_menu(ui));
+
+                if let Some(url) = &self.current {
+                    ui.separator();
+                    ui.weak(url);
+                }
+                if let Some(status) = &self.status {
+                    ui.separator();
+                    ui.weak(status_text(status));
+                }
+            });
+        });
+
+        egui::CentralPanel::default()
+            .frame(egui::Frame::NONE)
+            .show(ctx, |ui| match self.current.clone() {
+                Some(url) => {
+                    let size = ui.available_size();
+                    let widget = self.widget(&url).with_desired_size(size);
+                    // The remote UI has the keyboard, unless one of our own widgets took it
+                    if ctx.memory(|mem| mem.focused().is_none()) {
+                        widget.request_focus(ctx);
+                    }
+                    self.status = Some(widget.show(ui).status);
+                }
+                None => {
+                    self.status = None;
+                    self.connect_ui(ui);
+                }
+            });
+    }
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, eframe::APP_KEY, self);
+    }
+}
+
+fn status_text(status: &ConnectionStatus) -> String {
+    match status {
+        ConnectionStatus::Connecting => "Connecting…".into(),
+        ConnectionStatus::Connected { rtt: Some(rtt) } => {
+            format!("Connected, {:.0} ms", rtt.as_secs_f64() * 1e3)
+        }
+        ConnectionStatus::Connected { rtt: None } => "Connected".into(),
+        ConnectionStatus::Reconnecting { attempt, .. } => format!("Reconnecting (attempt {attempt})…"),
+        ConnectionStatus::Failed { .. } => "Disconnected".into(),
+    }
+}
+
+fn stats_ui(ui: &mut egui::Ui, stats: &ConnectionStats) {
+    egui::Grid::new("stats").num_columns(2).show(ui, |ui| {
+        ui.label("Format");
+        ui.label(format!("{:?}", stats.format));
+        ui.end_row();
+
+        if let Some(hello) = &stats.hello {
+            ui.label("Codec");
+            ui.label(format!("{:?}", hello.codec));
+            ui.end_row();
+
+            ui.label("Shapes");
+            ui.label(format!("{:?}", hello.shape_encoding));
+            ui.end_row();
+        }
+
+        let traffic = &stats.traffic;
+
+        ui.label("Round trip");
+        ui.label(match traffic.rtt {
+            Some(rtt) => format!("{:.1} ms", rtt.as_secs_f64() * 1e3),
+            None => "Not measured yet".into(),
+        });
+        ui.end_row();
+
+        ui.label("Received");
+        ui.label(format!(
+            "{} messages, {}",
+            traffic.messages_received,
+            format_bytes(traffic.bytes_received)
+        ));
+        ui.end_row();
+
+        ui.label("Sent");
+        ui.label(format!(
+            "{} messages, {}",
+            traffic.messages_sent,
+            format_bytes(traffic.bytes_sent)
+        ));
+        ui.end_row();
+
+        ui.label("Compression");
+        ui.label(format!("{:.1}x", traffic.compression_ratio()));
+        ui.end_row();
+
+        ui.label("Frames");
+        ui.label(format!(
+            "{} ({:.0} fps)",
+            stats.frames_decoded, traffic.fps
+        ));
+        ui.end_row();
+
+        ui.label("Updates");
+        ui.label(format!(
+            "{} full, {} partial",
+            traffic.full_updates, traffic.partial_updates
+        ));
+        ui.end_row();
+
+        ui.label("Shapes reused");
+        ui.label(format!("{:.0}%", traffic.reference_hit_rate() * 100.0));
+        ui.end_row();
+    });
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1_000 => format!("{bytes} B"),
+        1_000..1_000_000 => format!("{:.1} kB", bytes as f64 / 1e3),
+        _ => format!("{:.1} MB", bytes as f64 / 1e6),
+    }
+}